@@ -0,0 +1,939 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::{
+    episode::{Episode, EpisodeError, PayloadMetadata},
+    pki::PubKey,
+};
+use log::info;
+use std::str::FromStr;
+
+#[derive(Debug, BorshDeserialize, BorshSerialize)]
+pub enum ChessError {
+    IllegalMove,
+    InvalidNotation,
+    NotPlayersTurn,
+    GameOver,
+    NoTimeout,
+    NoUndo,
+}
+
+impl std::fmt::Display for ChessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChessError::IllegalMove => write!(f, "Move is not legal in this position."),
+            ChessError::InvalidNotation => write!(f, "Move is not in UCI notation (e.g. e2e4, e7e8q)."),
+            ChessError::NotPlayersTurn => write!(f, "It's not this player's turn."),
+            ChessError::GameOver => write!(f, "The game is already over."),
+            ChessError::NoTimeout => write!(f, "The opponent has not run out of time."),
+            ChessError::NoUndo => write!(f, "There is no move to undo or pending undo request to accept."),
+        }
+    }
+}
+
+impl std::error::Error for ChessError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Color {
+    White,
+    Black,
+}
+
+impl Color {
+    pub fn opponent(self) -> Self {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+
+    /// Rank direction in which pawns of this color advance
+    fn forward(self) -> i8 {
+        match self {
+            Color::White => 1,
+            Color::Black => -1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum PieceKind {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Piece {
+    pub color: Color,
+    pub kind: PieceKind,
+}
+
+impl Piece {
+    fn symbol(&self) -> char {
+        let c = match self.kind {
+            PieceKind::Pawn => 'p',
+            PieceKind::Knight => 'n',
+            PieceKind::Bishop => 'b',
+            PieceKind::Rook => 'r',
+            PieceKind::Queen => 'q',
+            PieceKind::King => 'k',
+        };
+        if self.color == Color::White {
+            c.to_ascii_uppercase()
+        } else {
+            c
+        }
+    }
+}
+
+/// Board square index, `rank * 8 + file` with a1 = 0 and h8 = 63
+pub type Square = u8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ChessMove {
+    pub from: Square,
+    pub to: Square,
+    pub promotion: Option<PieceKind>,
+}
+
+impl FromStr for ChessMove {
+    type Err = ChessError;
+
+    /// Parses a move in UCI notation, e.g. `e2e4` or `e7e8q`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn square(s: &[u8]) -> Option<Square> {
+            match s {
+                [file @ b'a'..=b'h', rank @ b'1'..=b'8'] => Some((rank - b'1') * 8 + (file - b'a')),
+                _ => None,
+            }
+        }
+        let s = s.trim().as_bytes();
+        if s.len() != 4 && s.len() != 5 {
+            return Err(ChessError::InvalidNotation);
+        }
+        let (Some(from), Some(to)) = (square(&s[0..2]), square(&s[2..4])) else {
+            return Err(ChessError::InvalidNotation);
+        };
+        let promotion = match s.get(4) {
+            None => None,
+            Some(b'n') => Some(PieceKind::Knight),
+            Some(b'b') => Some(PieceKind::Bishop),
+            Some(b'r') => Some(PieceKind::Rook),
+            Some(b'q') => Some(PieceKind::Queen),
+            Some(_) => return Err(ChessError::InvalidNotation),
+        };
+        Ok(Self { from, to, promotion })
+    }
+}
+
+impl std::fmt::Display for ChessMove {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = |sq: Square| format!("{}{}", (b'a' + sq % 8) as char, sq / 8 + 1);
+        write!(f, "{}{}", name(self.from), name(self.to))?;
+        if let Some(kind) = self.promotion {
+            write!(f, "{}", Piece { color: Color::Black, kind }.symbol())?;
+        }
+        Ok(())
+    }
+}
+
+/// Default number of DAA scores (roughly 10 minutes at 10 BPS) a player has to move before the opponent may claim the win
+pub const DEFAULT_TURN_TIMEOUT_DAA: u64 = 6000;
+
+/// Shortest and longest supported turn timeouts, roughly a minute and a day at 10 BPS
+pub const MIN_TURN_TIMEOUT_DAA: u64 = 600;
+pub const MAX_TURN_TIMEOUT_DAA: u64 = 864_000;
+
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
+pub enum ChessCommand {
+    Move(ChessMove),
+    /// Claims the win after the opponent let their turn deadline pass
+    ClaimTimeout,
+    /// Concedes the game, which either player may do at any time while it is in progress
+    Resign,
+    /// Asks the opponent to take back the last move, which must be the requester's own
+    RequestUndo,
+    /// Takes back the opponent's last move following their request, handing the turn back to them
+    AcceptUndo,
+}
+
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub enum ChessRollback {
+    Move { prev_timestamp: u64, prev_turn_daa: u64 },
+    ClaimTimeout,
+    Resign,
+    RequestUndo { prev_request: Option<UndoRequest> },
+    AcceptUndo { undone: Position, request: UndoRequest, prev_timestamp: u64, prev_turn_daa: u64 },
+}
+
+/// A pending undo request, only valid as long as no move was made since, i.e. while the history length
+/// is unchanged
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct UndoRequest {
+    pub player: PubKey,
+    pub history_len: usize,
+}
+
+const KNIGHT_STEPS: [(i8, i8); 8] = [(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)];
+const KING_STEPS: [(i8, i8); 8] = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+const ROOK_DIRECTIONS: [(i8, i8); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+const BISHOP_DIRECTIONS: [(i8, i8); 4] = [(1, 1), (-1, 1), (-1, -1), (1, -1)];
+const PROMOTIONS: [PieceKind; 4] = [PieceKind::Queen, PieceKind::Rook, PieceKind::Bishop, PieceKind::Knight];
+
+/// Castling rights indices: white kingside, white queenside, black kingside, black queenside
+const WHITE_KINGSIDE: usize = 0;
+const WHITE_QUEENSIDE: usize = 1;
+const BLACK_KINGSIDE: usize = 2;
+const BLACK_QUEENSIDE: usize = 3;
+
+/// Returns the square at a (file, rank) offset from `sq`, if it is still on the board
+fn offset(sq: Square, (df, dr): (i8, i8)) -> Option<Square> {
+    let file = (sq % 8) as i8 + df;
+    let rank = (sq / 8) as i8 + dr;
+    ((0..8).contains(&file) && (0..8).contains(&rank)).then_some((rank * 8 + file) as Square)
+}
+
+/// Everything which determines the legal continuations of a game, and hence position identity for the
+/// repetition rule. The halfmove clock is excluded from comparisons.
+#[derive(Clone, Debug, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Position {
+    pub board: [Option<Piece>; 64],
+    pub side_to_move: Color,
+    castling: [bool; 4],
+    en_passant: Option<Square>,
+    halfmove_clock: u32,
+}
+
+impl PartialEq for Position {
+    fn eq(&self, other: &Self) -> bool {
+        self.board == other.board
+            && self.side_to_move == other.side_to_move
+            && self.castling == other.castling
+            && self.en_passant == other.en_passant
+    }
+}
+
+impl Position {
+    pub fn initial() -> Self {
+        let back_rank = [
+            PieceKind::Rook,
+            PieceKind::Knight,
+            PieceKind::Bishop,
+            PieceKind::Queen,
+            PieceKind::King,
+            PieceKind::Bishop,
+            PieceKind::Knight,
+            PieceKind::Rook,
+        ];
+        let mut board = [None; 64];
+        for (file, kind) in back_rank.into_iter().enumerate() {
+            board[file] = Some(Piece { color: Color::White, kind });
+            board[8 + file] = Some(Piece { color: Color::White, kind: PieceKind::Pawn });
+            board[48 + file] = Some(Piece { color: Color::Black, kind: PieceKind::Pawn });
+            board[56 + file] = Some(Piece { color: Color::Black, kind });
+        }
+        Self { board, side_to_move: Color::White, castling: [true; 4], en_passant: None, halfmove_clock: 0 }
+    }
+
+    fn king_square(&self, color: Color) -> Option<Square> {
+        (0..64).find(|&sq| self.board[sq as usize] == Some(Piece { color, kind: PieceKind::King }))
+    }
+
+    pub fn in_check(&self, color: Color) -> bool {
+        self.king_square(color).is_some_and(|sq| self.is_attacked(sq, color.opponent()))
+    }
+
+    /// Whether any piece of color `by` attacks `sq`
+    fn is_attacked(&self, sq: Square, by: Color) -> bool {
+        let holds = |sq: Option<Square>, kinds: &[PieceKind]| {
+            sq.and_then(|sq| self.board[sq as usize]).is_some_and(|p| p.color == by && kinds.contains(&p.kind))
+        };
+        // A pawn attacks diagonally forward, so look diagonally backward from its perspective
+        if [-1, 1].into_iter().any(|df| holds(offset(sq, (df, -by.forward())), &[PieceKind::Pawn])) {
+            return true;
+        }
+        if KNIGHT_STEPS.into_iter().any(|step| holds(offset(sq, step), &[PieceKind::Knight])) {
+            return true;
+        }
+        if KING_STEPS.into_iter().any(|step| holds(offset(sq, step), &[PieceKind::King])) {
+            return true;
+        }
+        let slides = |directions: [(i8, i8); 4], kinds: &[PieceKind]| {
+            directions.into_iter().any(|dir| {
+                let mut cur = offset(sq, dir);
+                while let Some(s) = cur {
+                    if self.board[s as usize].is_some() {
+                        return holds(Some(s), kinds);
+                    }
+                    cur = offset(s, dir);
+                }
+                false
+            })
+        };
+        slides(ROOK_DIRECTIONS, &[PieceKind::Rook, PieceKind::Queen])
+            || slides(BISHOP_DIRECTIONS, &[PieceKind::Bishop, PieceKind::Queen])
+    }
+
+    /// Moves obeying piece movement rules, which may still leave the mover's king in check
+    fn pseudo_legal_moves(&self) -> Vec<ChessMove> {
+        let color = self.side_to_move;
+        let mut moves = Vec::new();
+        let target = |sq: Square| self.board[sq as usize].map(|p| p.color);
+        for from in 0..64u8 {
+            let Some(piece) = self.board[from as usize].filter(|p| p.color == color) else {
+                continue;
+            };
+            match piece.kind {
+                PieceKind::Pawn => {
+                    let last_rank = if color == Color::White { 7 } else { 0 };
+                    let mut push = |to: Square| {
+                        if to / 8 == last_rank {
+                            moves.extend(PROMOTIONS.map(|kind| ChessMove { from, to, promotion: Some(kind) }));
+                        } else {
+                            moves.push(ChessMove { from, to, promotion: None });
+                        }
+                    };
+                    if let Some(to) = offset(from, (0, color.forward())).filter(|&to| target(to).is_none()) {
+                        push(to);
+                        let start_rank = if color == Color::White { 1 } else { 6 };
+                        if from / 8 == start_rank {
+                            if let Some(to) = offset(to, (0, color.forward())).filter(|&to| target(to).is_none()) {
+                                push(to);
+                            }
+                        }
+                    }
+                    for df in [-1, 1] {
+                        if let Some(to) = offset(from, (df, color.forward())) {
+                            if target(to) == Some(color.opponent()) || self.en_passant == Some(to) {
+                                push(to);
+                            }
+                        }
+                    }
+                }
+                PieceKind::Knight | PieceKind::King => {
+                    let steps = if piece.kind == PieceKind::Knight { KNIGHT_STEPS } else { KING_STEPS };
+                    for to in steps.into_iter().filter_map(|step| offset(from, step)) {
+                        if target(to) != Some(color) {
+                            moves.push(ChessMove { from, to, promotion: None });
+                        }
+                    }
+                }
+                PieceKind::Bishop | PieceKind::Rook | PieceKind::Queen => {
+                    let directions = match piece.kind {
+                        PieceKind::Bishop => &BISHOP_DIRECTIONS[..],
+                        PieceKind::Rook => &ROOK_DIRECTIONS[..],
+                        _ => &KING_STEPS[..],
+                    };
+                    for &dir in directions {
+                        let mut cur = offset(from, dir);
+                        while let Some(to) = cur {
+                            match target(to) {
+                                None => moves.push(ChessMove { from, to, promotion: None }),
+                                Some(c) => {
+                                    if c != color {
+                                        moves.push(ChessMove { from, to, promotion: None });
+                                    }
+                                    break;
+                                }
+                            }
+                            cur = offset(to, dir);
+                        }
+                    }
+                }
+            }
+        }
+        self.castling_moves(&mut moves);
+        moves
+    }
+
+    /// Castling requires the right, empty squares between king and rook, and that the king is neither in
+    /// check nor passes through an attacked square. Landing in check is excluded by the legality filter.
+    fn castling_moves(&self, moves: &mut Vec<ChessMove>) {
+        let color = self.side_to_move;
+        let (base, kingside, queenside) = match color {
+            Color::White => (0, WHITE_KINGSIDE, WHITE_QUEENSIDE),
+            Color::Black => (56, BLACK_KINGSIDE, BLACK_QUEENSIDE),
+        };
+        let king = base + 4;
+        if self.board[king as usize] != Some(Piece { color, kind: PieceKind::King }) || self.is_attacked(king, color.opponent()) {
+            return;
+        }
+        let empty = |squares: &[Square]| squares.iter().all(|&sq| self.board[(base + sq) as usize].is_none());
+        let safe = |sq: Square| !self.is_attacked(base + sq, color.opponent());
+        if self.castling[kingside] && empty(&[5, 6]) && safe(5) {
+            moves.push(ChessMove { from: king, to: base + 6, promotion: None });
+        }
+        if self.castling[queenside] && empty(&[1, 2, 3]) && safe(3) {
+            moves.push(ChessMove { from: king, to: base + 2, promotion: None });
+        }
+    }
+
+    /// Plays a pseudo-legal move, updating castling rights, the en passant square and the halfmove clock
+    fn apply(&mut self, mv: &ChessMove) {
+        let Some(piece) = self.board[mv.from as usize].take() else {
+            return;
+        };
+        let capture = self.board[mv.to as usize].is_some();
+        let mut en_passant = None;
+        match piece.kind {
+            PieceKind::Pawn => {
+                if Some(mv.to) == self.en_passant {
+                    // The captured pawn sits beside the mover, behind the en passant square
+                    self.board[(mv.to as i8 - 8 * piece.color.forward()) as usize] = None;
+                }
+                if mv.from.abs_diff(mv.to) == 16 {
+                    en_passant = Some((mv.from + mv.to) / 2);
+                }
+            }
+            PieceKind::King if mv.from.abs_diff(mv.to) == 2 => {
+                let (rook_from, rook_to) = if mv.to > mv.from { (mv.from + 3, mv.from + 1) } else { (mv.from - 4, mv.from - 1) };
+                self.board[rook_to as usize] = self.board[rook_from as usize].take();
+            }
+            _ => {}
+        }
+        self.board[mv.to as usize] = Some(Piece { color: piece.color, kind: mv.promotion.unwrap_or(piece.kind) });
+
+        // Any move from or to a corner or king square forfeits the corresponding rights
+        for (right, squares) in
+            [(WHITE_KINGSIDE, [4, 7]), (WHITE_QUEENSIDE, [4, 0]), (BLACK_KINGSIDE, [60, 63]), (BLACK_QUEENSIDE, [60, 56])]
+        {
+            if squares.contains(&mv.from) || squares.contains(&mv.to) {
+                self.castling[right] = false;
+            }
+        }
+
+        self.halfmove_clock = if piece.kind == PieceKind::Pawn || capture { 0 } else { self.halfmove_clock + 1 };
+        self.en_passant = en_passant;
+        self.side_to_move = self.side_to_move.opponent();
+    }
+
+    pub fn legal_moves(&self) -> Vec<ChessMove> {
+        let color = self.side_to_move;
+        self.pseudo_legal_moves()
+            .into_iter()
+            .filter(|mv| {
+                let mut next = self.clone();
+                next.apply(mv);
+                !next.in_check(color)
+            })
+            .collect()
+    }
+
+    /// Plays a legal move. The en passant square is only kept when the capture is actually legal, so that
+    /// positions compare equal for the repetition rule exactly when they should.
+    fn play(&mut self, mv: &ChessMove) {
+        self.apply(mv);
+        if let Some(ep) = self.en_passant {
+            if !self.legal_moves().iter().any(|m| m.to == ep && self.board[m.from as usize].is_some_and(|p| p.kind == PieceKind::Pawn))
+            {
+                self.en_passant = None;
+            }
+        }
+    }
+
+    /// Neither side can possibly checkmate: bare kings, or a single minor piece beside them
+    fn insufficient_material(&self) -> bool {
+        let mut minors = 0;
+        for piece in self.board.iter().flatten() {
+            match piece.kind {
+                PieceKind::King => {}
+                PieceKind::Knight | PieceKind::Bishop => minors += 1,
+                _ => return false,
+            }
+        }
+        minors <= 1
+    }
+
+    pub fn print(&self) {
+        for rank in (0..8).rev() {
+            print!("{} ", rank + 1);
+            for file in 0..8 {
+                print!(" {}", self.board[rank * 8 + file].map_or('.', |p| p.symbol()));
+            }
+            println!();
+        }
+        println!("   a b c d e f g h");
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum DrawReason {
+    Stalemate,
+    ThreefoldRepetition,
+    FiftyMoveRule,
+    InsufficientMaterial,
+}
+
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub enum ChessGameStatus {
+    InProgress(PubKey),
+    Winner(PubKey),
+    WonOnTime(PubKey),
+    /// Ended by the opponent of the given player resigning
+    WonByResignation(PubKey),
+    Draw(DrawReason),
+    /// The episode was not created with exactly two distinct participants, so it accepts no commands
+    Invalid,
+}
+
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct ChessState {
+    pub position: Position,
+    /// Missing for an invalid episode
+    pub white: Option<PubKey>,
+    pub status: ChessGameStatus,
+    /// DAA score after which the player to move may be claimed out of time
+    pub turn_deadline: u64,
+    /// The player asking to take back their last move, if any
+    pub undo_requested: Option<PubKey>,
+}
+
+impl ChessState {
+    /// Checks a move by `player` against this state, allowing invalid moves to be rejected locally
+    /// before paying the fee of a transaction the engine would reject anyway
+    pub fn check_move(&self, mv: &ChessMove, player: PubKey) -> Result<(), ChessError> {
+        match self.status {
+            ChessGameStatus::InProgress(pk) if pk != player => return Err(ChessError::NotPlayersTurn),
+            ChessGameStatus::InProgress(_) => {}
+            _ => return Err(ChessError::GameOver),
+        }
+        if !self.position.legal_moves().contains(mv) {
+            return Err(ChessError::IllegalMove);
+        }
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        self.position.print();
+        let color = |pk: PubKey| if Some(pk) == self.white { "white" } else { "black" };
+        match self.status {
+            ChessGameStatus::InProgress(pk) if self.position.in_check(self.position.side_to_move) => {
+                println!("{} to move, in check", color(pk))
+            }
+            ChessGameStatus::InProgress(pk) => println!("{} to move", color(pk)),
+            ChessGameStatus::Winner(pk) => println!("checkmate, winner: {} [{}]", color(pk), pk),
+            ChessGameStatus::WonOnTime(pk) => println!("winner on time: {} [{}]", color(pk), pk),
+            ChessGameStatus::WonByResignation(pk) => println!("opponent resigned, winner: {} [{}]", color(pk), pk),
+            ChessGameStatus::Draw(reason) => println!("---- Draw ({:?}) ----", reason),
+            ChessGameStatus::Invalid => println!("---- Invalid episode ----"),
+        }
+    }
+}
+
+/// Rule variants, chosen by the creator of the episode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ChessRules {
+    /// Neither side may castle
+    pub no_castling: bool,
+    /// Number of DAA scores a player has to move before the opponent may claim the win, clamped to
+    /// `MIN_TURN_TIMEOUT_DAA..=MAX_TURN_TIMEOUT_DAA`
+    pub turn_timeout: u64,
+}
+
+impl Default for ChessRules {
+    fn default() -> Self {
+        Self { no_castling: false, turn_timeout: DEFAULT_TURN_TIMEOUT_DAA }
+    }
+}
+
+impl ChessRules {
+    fn sanitized(self) -> Self {
+        Self { turn_timeout: self.turn_timeout.clamp(MIN_TURN_TIMEOUT_DAA, MAX_TURN_TIMEOUT_DAA), ..self }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Chess {
+    pub(crate) players: Vec<PubKey>,
+    rules: ChessRules,
+    pub(crate) position: Position,
+    /// Positions before each move, used both for rollback and for detecting repetitions
+    history: Vec<Position>,
+    timestamp: u64,
+    turn_daa: u64,
+    timeout_winner: Option<PubKey>,
+    resigned: Option<Color>,
+    undo_request: Option<UndoRequest>,
+}
+
+impl Episode for Chess {
+    type Command = ChessCommand;
+    type CommandRollback = ChessRollback;
+    type CommandError = ChessError;
+    type InitParams = ChessRules;
+
+    fn initialize(participants: Vec<PubKey>, params: &ChessRules, metadata: &PayloadMetadata) -> Self {
+        info!("[Chess] initialize: {:?}, {:?}", participants, params);
+        let mut position = Position::initial();
+        if params.no_castling {
+            position.castling = [false; 4];
+        }
+        Self {
+            players: participants,
+            rules: params.sanitized(),
+            position,
+            history: Vec::new(),
+            timestamp: metadata.accepting_time,
+            turn_daa: metadata.accepting_daa,
+            timeout_winner: None,
+            resigned: None,
+            undo_request: None,
+        }
+    }
+
+    fn execute(
+        &mut self,
+        cmd: &Self::Command,
+        authorization: Option<PubKey>,
+        metadata: &PayloadMetadata,
+    ) -> Result<Self::CommandRollback, EpisodeError<Self::CommandError>> {
+        let Some(player) = authorization else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        let Some(players) = self.players() else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        if !players.contains(&player) {
+            return Err(EpisodeError::Unauthorized);
+        }
+        let state = self.poll();
+        let mv = match cmd {
+            ChessCommand::Move(mv) => mv,
+            ChessCommand::ClaimTimeout => {
+                match state.status {
+                    ChessGameStatus::InProgress(pk) if pk != player && metadata.accepting_daa > state.turn_deadline => {}
+                    ChessGameStatus::InProgress(_) => return Err(EpisodeError::InvalidCommand(ChessError::NoTimeout)),
+                    _ => return Err(EpisodeError::InvalidCommand(ChessError::GameOver)),
+                }
+                info!("[Chess] timeout claimed by {:?}", player);
+                self.timeout_winner = Some(player);
+                return Ok(ChessRollback::ClaimTimeout);
+            }
+            ChessCommand::Resign => {
+                if !matches!(state.status, ChessGameStatus::InProgress(_)) {
+                    return Err(EpisodeError::InvalidCommand(ChessError::GameOver));
+                }
+                info!("[Chess] resigned by {:?}", player);
+                self.resigned = Some(if player == players[Color::White as usize] { Color::White } else { Color::Black });
+                return Ok(ChessRollback::Resign);
+            }
+            ChessCommand::RequestUndo => {
+                match state.status {
+                    ChessGameStatus::InProgress(pk) if pk != player && !self.history.is_empty() => {}
+                    ChessGameStatus::InProgress(_) => return Err(EpisodeError::InvalidCommand(ChessError::NoUndo)),
+                    _ => return Err(EpisodeError::InvalidCommand(ChessError::GameOver)),
+                }
+                info!("[Chess] undo requested by {:?}", player);
+                let prev_request = self.undo_request.replace(UndoRequest { player, history_len: self.history.len() });
+                return Ok(ChessRollback::RequestUndo { prev_request });
+            }
+            ChessCommand::AcceptUndo => {
+                match state.status {
+                    ChessGameStatus::InProgress(pk) if pk == player && state.undo_requested.is_some() => {}
+                    ChessGameStatus::InProgress(_) => return Err(EpisodeError::InvalidCommand(ChessError::NoUndo)),
+                    _ => return Err(EpisodeError::InvalidCommand(ChessError::GameOver)),
+                }
+                info!("[Chess] undo accepted by {:?}", player);
+                let (Some(request), Some(position)) = (self.undo_request.take(), self.history.pop()) else {
+                    unreachable!("a pending request implies a non-empty history");
+                };
+                let undone = std::mem::replace(&mut self.position, position);
+                // The requester gets a fresh deadline for replaying their turn
+                let prev_timestamp = std::mem::replace(&mut self.timestamp, metadata.accepting_time);
+                let prev_turn_daa = std::mem::replace(&mut self.turn_daa, metadata.accepting_daa);
+                return Ok(ChessRollback::AcceptUndo { undone, request, prev_timestamp, prev_turn_daa });
+            }
+        };
+        state.check_move(mv, player).map_err(EpisodeError::InvalidCommand)?;
+
+        info!("[Chess] execute: {:?}, {}", player, mv);
+
+        self.history.push(self.position.clone());
+        self.position.play(mv);
+
+        let prev_timestamp = self.timestamp;
+        self.timestamp = metadata.accepting_time;
+        let prev_turn_daa = self.turn_daa;
+        self.turn_daa = metadata.accepting_daa;
+
+        Ok(ChessRollback::Move { prev_timestamp, prev_turn_daa })
+    }
+
+    fn rollback(&mut self, rollback: ChessRollback) -> bool {
+        match rollback {
+            ChessRollback::Move { prev_timestamp, prev_turn_daa } => {
+                let Some(position) = self.history.pop() else {
+                    return false;
+                };
+                self.position = position;
+                self.timestamp = prev_timestamp;
+                self.turn_daa = prev_turn_daa;
+                true
+            }
+            ChessRollback::ClaimTimeout => self.timeout_winner.take().is_some(),
+            ChessRollback::Resign => self.resigned.take().is_some(),
+            ChessRollback::RequestUndo { prev_request } => {
+                self.undo_request = prev_request;
+                true
+            }
+            ChessRollback::AcceptUndo { undone, request, prev_timestamp, prev_turn_daa } => {
+                self.history.push(std::mem::replace(&mut self.position, undone));
+                self.undo_request = Some(request);
+                self.timestamp = prev_timestamp;
+                self.turn_daa = prev_turn_daa;
+                true
+            }
+        }
+    }
+}
+
+impl Chess {
+    pub fn poll(&self) -> ChessState {
+        ChessState {
+            position: self.position.clone(),
+            white: self.players().map(|players| players[Color::White as usize]),
+            status: self.status(),
+            turn_deadline: self.turn_daa + self.rules.turn_timeout,
+            undo_requested: self.undo_request.filter(|r| r.history_len == self.history.len()).map(|r| r.player),
+        }
+    }
+
+    /// The two players indexed by color, the first participant playing white. Participants come straight from
+    /// the episode tx, so any other list makes the episode invalid
+    fn players(&self) -> Option<[PubKey; 2]> {
+        match self.players[..] {
+            [white, black] if white != black => Some([white, black]),
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> ChessGameStatus {
+        let Some(players) = self.players() else {
+            return ChessGameStatus::Invalid;
+        };
+        let player = |color: Color| players[color as usize];
+        if let Some(winner) = self.timeout_winner {
+            return ChessGameStatus::WonOnTime(winner);
+        }
+        if let Some(color) = self.resigned {
+            return ChessGameStatus::WonByResignation(player(color.opponent()));
+        }
+        let to_move = self.position.side_to_move;
+        if self.position.legal_moves().is_empty() {
+            return if self.position.in_check(to_move) {
+                ChessGameStatus::Winner(player(to_move.opponent()))
+            } else {
+                ChessGameStatus::Draw(DrawReason::Stalemate)
+            };
+        }
+        // Draws are applied automatically rather than on claim, as there is no one to claim them in between moves
+        if self.position.halfmove_clock >= 100 {
+            return ChessGameStatus::Draw(DrawReason::FiftyMoveRule);
+        }
+        if self.history.iter().filter(|&p| *p == self.position).count() >= 2 {
+            return ChessGameStatus::Draw(DrawReason::ThreefoldRepetition);
+        }
+        if self.position.insufficient_material() {
+            return ChessGameStatus::Draw(DrawReason::InsufficientMaterial);
+        }
+        ChessGameStatus::InProgress(player(to_move))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kdapp::pki::generate_keypair;
+
+    fn metadata() -> PayloadMetadata {
+        PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() }
+    }
+
+    fn mv(uci: &str) -> ChessCommand {
+        ChessCommand::Move(uci.parse().unwrap())
+    }
+
+    /// Plays UCI moves alternately for the two players
+    fn play(game: &mut Chess, moves: &str) -> Vec<ChessRollback> {
+        let players = [game.players[0], game.players[1]];
+        moves
+            .split_whitespace()
+            .map(|uci| {
+                let player = players[game.position.side_to_move as usize];
+                game.execute(&mv(uci), Some(player), &metadata()).unwrap_or_else(|err| panic!("{}: {}", uci, err))
+            })
+            .collect()
+    }
+
+    fn new_game() -> (Chess, PubKey, PubKey) {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        (Chess::initialize(vec![p1, p2], &ChessRules::default(), &metadata()), p1, p2)
+    }
+
+    /// Counts leaf nodes of the legal move tree
+    fn perft(position: &Position, depth: u32) -> usize {
+        if depth == 0 {
+            return 1;
+        }
+        position
+            .legal_moves()
+            .iter()
+            .map(|mv| {
+                let mut next = position.clone();
+                next.apply(mv);
+                perft(&next, depth - 1)
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_chess_perft() {
+        let position = Position::initial();
+        assert_eq!((perft(&position, 1), perft(&position, 2), perft(&position, 3)), (20, 400, 8902));
+    }
+
+    #[test]
+    fn test_chess_checkmate_and_rollback() {
+        let (mut game, p1, p2) = new_game();
+        assert!(matches!(
+            game.execute(&mv("e7e5"), Some(p2), &metadata()),
+            Err(EpisodeError::InvalidCommand(ChessError::NotPlayersTurn))
+        ));
+        let snapshot = game.clone();
+        let mut rollbacks = play(&mut game, "f2f3 e7e5 g2g4 d8h4");
+        assert!(matches!(game.poll().status, ChessGameStatus::Winner(pk) if pk == p2));
+        assert!(matches!(game.execute(&mv("a2a3"), Some(p1), &metadata()), Err(EpisodeError::InvalidCommand(ChessError::GameOver))));
+
+        while let Some(rollback) = rollbacks.pop() {
+            assert!(game.rollback(rollback));
+        }
+        assert_eq!(snapshot, game);
+        assert!(!game.rollback(ChessRollback::Move { prev_timestamp: 0, prev_turn_daa: 0 }));
+    }
+
+    #[test]
+    fn test_chess_special_moves() {
+        let (mut game, _p1, _p2) = new_game();
+        // Kingside castling for white, en passant by white, then promotion with capture
+        play(&mut game, "e2e4 a7a6 e4e5 d7d5 e5d6 a6a5 g1f3 a5a4 f1e2 a4a3 e1g1 a3b2 d6c7 b2a1q c7b8n");
+        let board = &game.position.board;
+        assert_eq!(board[6], Some(Piece { color: Color::White, kind: PieceKind::King }));
+        assert_eq!(board[5], Some(Piece { color: Color::White, kind: PieceKind::Rook }));
+        assert_eq!(board[0], Some(Piece { color: Color::Black, kind: PieceKind::Queen }));
+        assert_eq!(board[57], Some(Piece { color: Color::White, kind: PieceKind::Knight }));
+        assert!(board[35].is_none(), "pawn captured en passant");
+
+        // Castling through an attacked square is illegal
+        let (mut game, p1, _p2) = new_game();
+        play(&mut game, "e2e4 e7e6 g1f3 d8g5 f1c4 g5g2");
+        assert!(matches!(game.poll().check_move(&"e1g1".parse().unwrap(), p1), Err(ChessError::IllegalMove)));
+
+        // Castling is disabled altogether by the no-castling variant
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let mut game = Chess::initialize(vec![p1, p2], &ChessRules { no_castling: true, ..Default::default() }, &metadata());
+        play(&mut game, "e2e4 e7e5 g1f3 g8f6 f1c4 f8c5");
+        assert!(matches!(game.poll().check_move(&"e1g1".parse().unwrap(), p1), Err(ChessError::IllegalMove)));
+    }
+
+    #[test]
+    fn test_chess_draws() {
+        let (mut game, _p1, _p2) = new_game();
+        play(&mut game, "g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1");
+        assert!(matches!(game.poll().status, ChessGameStatus::InProgress(_)));
+        play(&mut game, "f6g8");
+        assert!(matches!(game.poll().status, ChessGameStatus::Draw(DrawReason::ThreefoldRepetition)));
+
+        // Black is stalemated after the queen covers every escape square
+        let (mut game, _p1, _p2) = new_game();
+        play(&mut game, "e2e3 a7a5 d1h5 a8a6 h5a5 h7h5 h2h4 a6h6 a5c7 f7f6 c7d7 e8f7 d7b7 d8d3 b7b8 d3h7 b8c8 f7g6 c8e6");
+        assert!(matches!(game.poll().status, ChessGameStatus::Draw(DrawReason::Stalemate)));
+    }
+
+    #[test]
+    fn test_chess_notation() {
+        let mv: ChessMove = "e7e8q".parse().unwrap();
+        assert_eq!((mv.from, mv.to, mv.promotion), (52, 60, Some(PieceKind::Queen)));
+        assert_eq!(mv.to_string(), "e7e8q");
+        assert!("e7e9".parse::<ChessMove>().is_err() && "e2".parse::<ChessMove>().is_err());
+    }
+
+    #[test]
+    fn test_chess_timeout_and_resign() {
+        let (mut game, p1, p2) = new_game();
+        play(&mut game, "e2e4");
+        let late = PayloadMetadata { accepting_daa: DEFAULT_TURN_TIMEOUT_DAA + 1, ..metadata() };
+        let res = game.execute(&ChessCommand::ClaimTimeout, Some(p2), &late);
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(ChessError::NoTimeout))));
+        let res = game.execute(&ChessCommand::ClaimTimeout, Some(p1), &metadata());
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(ChessError::NoTimeout))));
+
+        let rollback = game.execute(&ChessCommand::ClaimTimeout, Some(p1), &late).unwrap();
+        assert!(matches!(game.poll().status, ChessGameStatus::WonOnTime(pk) if pk == p1));
+        assert!(matches!(game.execute(&mv("e7e5"), Some(p2), &late), Err(EpisodeError::InvalidCommand(ChessError::GameOver))));
+        assert!(game.rollback(rollback));
+        assert!(matches!(game.poll().status, ChessGameStatus::InProgress(pk) if pk == p2));
+
+        // Either player may resign, even when it is not their turn
+        let rollback = game.execute(&ChessCommand::Resign, Some(p1), &metadata()).unwrap();
+        assert!(matches!(game.poll().status, ChessGameStatus::WonByResignation(pk) if pk == p2));
+        assert!(game.rollback(rollback));
+        game.execute(&ChessCommand::Resign, Some(p2), &metadata()).unwrap();
+        assert!(matches!(game.poll().status, ChessGameStatus::WonByResignation(pk) if pk == p1));
+
+        // Out of range turn timeouts are clamped rather than trusted
+        for (turn_timeout, clamped) in [(0, MIN_TURN_TIMEOUT_DAA), (u64::MAX, MAX_TURN_TIMEOUT_DAA)] {
+            let game = Chess::initialize(vec![p1, p2], &ChessRules { turn_timeout, ..Default::default() }, &metadata());
+            assert_eq!(game.poll().turn_deadline, clamped);
+        }
+    }
+
+    #[test]
+    fn test_chess_undo() {
+        let (mut game, p1, p2) = new_game();
+        let undo = |game: &mut Chess, cmd, player| game.execute(&cmd, Some(player), &metadata());
+        assert!(matches!(undo(&mut game, ChessCommand::RequestUndo, p2), Err(EpisodeError::InvalidCommand(ChessError::NoUndo))));
+        play(&mut game, "e2e4 e7e5");
+
+        // Only the author of the last move may ask, and only the opponent may accept
+        assert!(matches!(undo(&mut game, ChessCommand::RequestUndo, p1), Err(EpisodeError::InvalidCommand(ChessError::NoUndo))));
+        undo(&mut game, ChessCommand::RequestUndo, p2).unwrap();
+        assert_eq!(game.poll().undo_requested, Some(p2));
+        assert!(matches!(undo(&mut game, ChessCommand::AcceptUndo, p2), Err(EpisodeError::InvalidCommand(ChessError::NoUndo))));
+
+        let snapshot = game.clone();
+        let rollback = undo(&mut game, ChessCommand::AcceptUndo, p1).unwrap();
+        let state = game.poll();
+        assert!(matches!(state.status, ChessGameStatus::InProgress(pk) if pk == p2));
+        assert_eq!((state.position.side_to_move, state.undo_requested), (Color::Black, None));
+        assert!(game.rollback(rollback));
+        assert_eq!(snapshot, game);
+        assert_eq!(game.poll().undo_requested, Some(p2));
+
+        // Playing on instead of accepting discards the request
+        play(&mut game, "g1f3");
+        assert_eq!(game.poll().undo_requested, None);
+        assert!(matches!(undo(&mut game, ChessCommand::AcceptUndo, p2), Err(EpisodeError::InvalidCommand(ChessError::NoUndo))));
+    }
+
+    #[test]
+    fn test_chess_invalid_players() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let (_s3, p3) = generate_keypair();
+        for players in [vec![], vec![p1], vec![p1, p1], vec![p1, p2, p3]] {
+            let mut game = Chess::initialize(players, &ChessRules::default(), &metadata());
+            assert!(matches!(game.poll().status, ChessGameStatus::Invalid));
+            for cmd in [mv("e2e4"), ChessCommand::Resign, ChessCommand::ClaimTimeout] {
+                assert!(matches!(game.execute(&cmd, Some(p1), &metadata()), Err(EpisodeError::Unauthorized)));
+            }
+        }
+
+        // A third key cannot act on a valid game
+        let (mut game, _p1, _p2) = new_game();
+        let late = PayloadMetadata { accepting_daa: DEFAULT_TURN_TIMEOUT_DAA + 1, ..metadata() };
+        for cmd in [ChessCommand::ClaimTimeout, ChessCommand::Resign, ChessCommand::RequestUndo] {
+            assert!(matches!(game.execute(&cmd, Some(p3), &late), Err(EpisodeError::Unauthorized)));
+        }
+    }
+}
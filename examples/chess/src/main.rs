@@ -0,0 +1,362 @@
+use clap::Parser;
+use kaspa_addresses::{Address, Version};
+use kaspa_wrpc_client::prelude::*;
+use log::*;
+use rand::Rng;
+use secp256k1::{Keypair, PublicKey, SecretKey};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use kdapp::{
+    engine::{self, EngineConfig, EpisodeMessage},
+    episode::{EpisodeError, EpisodeEventHandler, EpisodeId, PayloadMetadata},
+    generator::{self, PatternType, PrefixType},
+    pki::{generate_keypair, PubKey},
+    proxy::{self, connect_client, connect_client_with_failover, CommandSubmitter},
+    utils::{self, network::NetworkProfile},
+};
+
+use game::{Chess, ChessCommand, ChessGameStatus, ChessMove, ChessRules, ChessState, DEFAULT_TURN_TIMEOUT_DAA};
+
+pub mod game;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Kaspa schnorr private key
+    #[arg(short, long)]
+    kaspa_private_key: Option<String>,
+
+    /// Game private key
+    #[arg(short = 'g', long)]
+    game_private_key: Option<String>,
+
+    /// Game opponent public key
+    #[arg(short = 'o', long)]
+    game_opponent_key: Option<String>,
+
+    /// Disables castling for both sides, when initiating the game
+    #[arg(long, default_value_t = false)]
+    no_castling: bool,
+
+    /// Number of DAA scores each player has to move before the opponent may claim the win, when initiating the game
+    #[arg(long, default_value_t = DEFAULT_TURN_TIMEOUT_DAA)]
+    turn_timeout: u64,
+
+    /// Indicates whether to run the interaction over mainnet (default: testnet 10)
+    #[arg(short, long, default_value_t = false)]
+    mainnet: bool,
+
+    /// Fee in sompi attached to each game transaction. Defaults to the default fee of the network
+    #[arg(long)]
+    fee: Option<u64>,
+
+    /// Specifies the wRPC Kaspa Node URL to use. Usage: <wss://localhost>. Defaults to the Public Node Network (PNN).
+    /// May be repeated, in which case the first responsive node is used
+    #[arg(short, long)]
+    wrpc_url: Vec<String>,
+
+    /// Logging level for all subsystems {off, error, warn, info, debug, trace}
+    ///  -- You may also specify `<subsystem>=<level>,<subsystem2>=<level>,...` to set the log level for individual subsystems
+    #[arg(long = "loglevel", default_value = format!("info,{}=trace", env!("CARGO_PKG_NAME")))]
+    log_level: String,
+}
+
+#[tokio::main]
+async fn main() {
+    // Get CLI arguments
+    let args = Args::parse();
+
+    // Init logger
+    kaspa_core::log::init_logger(None, &args.log_level);
+
+    // Select network
+    let profile = if args.mainnet { NetworkProfile::MAINNET } else { NetworkProfile::TESTNET_10 };
+    let (network, prefix) = (profile.network_id, profile.prefix());
+
+    // Generate or obtain Kaspa private key
+    let kaspa_signer = if let Some(private_key_hex) = args.kaspa_private_key {
+        let mut private_key_bytes = [0u8; 32];
+        faster_hex::hex_decode(private_key_hex.as_bytes(), &mut private_key_bytes).unwrap();
+        Keypair::from_seckey_slice(secp256k1::SECP256K1, &private_key_bytes).unwrap()
+    } else {
+        let (sk, pk) = &secp256k1::generate_keypair(&mut rand::thread_rng());
+        info!(
+            "Generated private key {} and address {}. Send some funds to this address and rerun with `--kaspa-private-key {}`",
+            sk.display_secret(),
+            String::from(&Address::new(prefix, Version::PubKey, &pk.x_only_public_key().0.serialize())),
+            sk.display_secret()
+        );
+        if let Some(faucet_url) = profile.faucet_url {
+            info!("Testnet funds are available from the faucet at {}", faucet_url);
+        }
+        return;
+    };
+
+    // Extract Kaspa address
+    let kaspa_addr = Address::new(prefix, Version::PubKey, &kaspa_signer.x_only_public_key().0.serialize());
+
+    // Obtain game keys
+    let (sk, player_pk) = if let Some(game_key_hex) = args.game_private_key {
+        let pair = Keypair::from_str(&game_key_hex).unwrap();
+        (pair.secret_key(), PubKey(pair.public_key()))
+    } else {
+        let (sk, pk) = generate_keypair();
+        info!("Player private key: {}", sk.display_secret());
+        (sk, pk)
+    };
+
+    info!("Player public key: {}", player_pk);
+
+    // ... and opponent pk
+    let opponent_pk = args.game_opponent_key.map(|opponent_key_hex| PubKey(PublicKey::from_str(&opponent_key_hex).unwrap()));
+
+    // Connect kaspad clients
+    let kaspad = connect_client_with_failover(network, &args.wrpc_url).await.unwrap();
+    let player_kaspad = connect_client(network, kaspad.url()).await.unwrap(); // Pin to the same node
+
+    // Define channels and exit flag
+    let (sender, receiver) = channel();
+    let (response_sender, response_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let exit_signal = Arc::new(AtomicBool::new(false));
+    let exit_signal_receiver = exit_signal.clone();
+
+    // On Ctrl-C or SIGTERM, stop the listener which in turn signals the engine to exit
+    let interrupted = proxy::exit_on_ctrl_c(exit_signal.clone());
+
+    // Keep episodes for as long as their txs are retained by the node
+    let episode_lifetime = proxy::pruning_window(&kaspad).await.unwrap();
+    info!("Episode lifetime: {} DAA score units", episode_lifetime);
+
+    // Run the engine
+    let config = EngineConfig { episode_lifetime, ..Default::default() };
+    let mut engine = engine::Engine::<Chess, ChessHandler>::with_config(receiver, config);
+    let engine_task = tokio::task::spawn_blocking(move || {
+        engine.start(vec![ChessHandler { sender: response_sender, player: player_pk }]);
+    });
+
+    // Run the player task
+    let fee = args.fee.unwrap_or(profile.default_fee);
+    let rules = ChessRules { no_castling: args.no_castling, turn_timeout: args.turn_timeout };
+    let generator = generator::TransactionGenerator::new(kaspa_signer, PATTERN, PREFIX);
+    let submitter = CommandSubmitter::new(&player_kaspad, generator, kaspa_addr, profile, fee, opponent_pk.is_some()).await.unwrap();
+    let player_task = tokio::spawn(async move {
+        play_chess(player_kaspad, submitter, response_receiver, exit_signal, sk, player_pk, opponent_pk, rules).await;
+    });
+
+    // Run the kaspad listener
+    let engines = std::iter::once((PREFIX, (PATTERN, sender))).collect();
+    proxy::run_listener(kaspad, network, args.wrpc_url, engines, exit_signal_receiver).await;
+
+    engine_task.await.unwrap();
+    if interrupted.load(Ordering::Relaxed) {
+        // The player task might be blocked on stdin, so exit without awaiting it
+        std::process::exit(0);
+    }
+    player_task.await.unwrap();
+}
+
+const PREFIX: PrefixType = 1128810835;
+const PATTERN: PatternType = generator::pattern_from_prefix(PREFIX);
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A game state together with the metadata of the accepted transaction which produced it, if any
+type StateUpdate = (EpisodeId, ChessState, Option<PayloadMetadata>);
+
+struct ChessHandler {
+    sender: UnboundedSender<StateUpdate>,
+    player: PubKey, // The local player pubkey
+}
+
+impl EpisodeEventHandler<Chess> for ChessHandler {
+    fn on_initialize(&self, episode_id: kdapp::episode::EpisodeId, episode: &Chess) {
+        if episode.players.contains(&self.player) {
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+
+    fn on_command(
+        &self,
+        episode_id: kdapp::episode::EpisodeId,
+        episode: &Chess,
+        _cmd: &<Chess as kdapp::episode::Episode>::Command,
+        _authorization: Option<PubKey>,
+        metadata: &kdapp::episode::PayloadMetadata,
+    ) {
+        if episode.players.contains(&self.player) {
+            let _ = self.sender.send((episode_id, episode.poll(), Some(metadata.clone())));
+        }
+    }
+
+    fn on_rollback(&self, episode_id: kdapp::episode::EpisodeId, episode: &Chess, metadata: &kdapp::episode::PayloadMetadata) {
+        if episode.players.contains(&self.player) {
+            warn!("A DAG reorg reverted tx {} in episode {}", metadata.tx_id, episode_id);
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+
+    fn on_reject(
+        &self,
+        episode_id: kdapp::episode::EpisodeId,
+        episode: &Chess,
+        cmd: &<Chess as kdapp::episode::Episode>::Command,
+        authorization: Option<PubKey>,
+        error: &EpisodeError<<Chess as kdapp::episode::Episode>::CommandError>,
+        _metadata: &kdapp::episode::PayloadMetadata,
+    ) {
+        if authorization == Some(self.player) && error.is_authenticated() {
+            println!("Your command {:?} was rejected: {}", cmd, error);
+            // Wake the player task, which would otherwise keep waiting for the command to take effect
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+}
+
+/// Prints a game state along with when and where the transaction producing it was accepted
+fn print_update(state: &ChessState, metadata: &Option<PayloadMetadata>) {
+    state.print();
+    if let Some(metadata) = metadata {
+        println!("{}", utils::format_acceptance(metadata));
+    }
+}
+
+async fn play_chess(
+    kaspad: KaspaRpcClient,
+    mut submitter: CommandSubmitter,
+    mut response_receiver: UnboundedReceiver<StateUpdate>,
+    exit_signal: Arc<AtomicBool>,
+    sk: SecretKey,
+    player_pk: PubKey,
+    opponent_pk: Option<PubKey>,
+    rules: ChessRules,
+) {
+    // When opponent pk is passed, we are expected to initiate the game and play white
+    if let Some(opponent_pk) = opponent_pk {
+        let episode_id = rand::thread_rng().gen();
+        let new_episode =
+            EpisodeMessage::<Chess>::NewEpisode { episode_id, participants: vec![player_pk, opponent_pk], params: rules };
+        submitter.submit(&kaspad, &new_episode).await.unwrap();
+    }
+
+    // A closed channel means the engine has exited
+    let Some((episode_id, mut state, mut metadata)) = response_receiver.recv().await else {
+        return;
+    };
+    print_update(&state, &metadata);
+
+    let mut received_id = episode_id;
+
+    // Read stdin on a dedicated thread, so that input is also accepted while waiting for the opponent
+    let (input_sender, mut input_receiver) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines().map_while(Result::ok) {
+            if input_sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        let mut claimed = false;
+        while let ChessGameStatus::InProgress(pk) = state.status {
+            if received_id == episode_id && player_pk == pk {
+                break;
+            }
+            // Loop until our turn, claiming the win if the opponent lets their deadline pass
+            let update = tokio::select! {
+                update = response_receiver.recv() => update,
+                Some(input) = input_receiver.recv() => {
+                    if input.trim() != "undo" {
+                        println!("Waiting for the opponent, enter `undo` to ask for taking back your last move");
+                    } else if received_id == episode_id {
+                        let step = EpisodeMessage::<Chess>::new_signed_command(episode_id, ChessCommand::RequestUndo, sk, player_pk);
+                        submitter.submit(&kaspad, &step).await.unwrap();
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep(DEADLINE_POLL_INTERVAL) => {
+                    if !claimed
+                        && received_id == episode_id
+                        && proxy::virtual_daa_score(&kaspad).await.is_some_and(|daa| daa > state.turn_deadline)
+                    {
+                        println!("Opponent ran out of time, claiming the win");
+                        let step = EpisodeMessage::<Chess>::new_signed_command(episode_id, ChessCommand::ClaimTimeout, sk, player_pk);
+                        submitter.submit(&kaspad, &step).await.unwrap();
+                        claimed = true;
+                    }
+                    continue;
+                }
+            };
+            let Some(update) = update else {
+                return;
+            };
+            (received_id, state, metadata) = update;
+            if received_id == episode_id {
+                claimed = false;
+                print_update(&state, &metadata);
+            }
+        }
+
+        if !matches!(state.status, ChessGameStatus::InProgress(..)) {
+            exit_signal.store(true, Ordering::Relaxed);
+            break;
+        }
+
+        if state.undo_requested.is_some() {
+            println!("Opponent asks to take back their last move, enter `accept` to allow it");
+        }
+        let cmd = loop {
+            println!("Insert move before DAA score {}: [from][to][promotion], e.g. e2e4 or e7e8q, or `resign`", state.turn_deadline);
+            let Some(input) = input_receiver.recv().await else {
+                return;
+            };
+            match input.trim() {
+                "resign" => break ChessCommand::Resign,
+                "accept" if state.undo_requested.is_some() => break ChessCommand::AcceptUndo,
+                _ => {}
+            }
+            let mv = match input.parse::<ChessMove>() {
+                Ok(mv) => mv,
+                Err(err) => {
+                    println!("Invalid input: {}", err);
+                    continue;
+                }
+            };
+            // Validate locally before spending a fee on a move the engine would reject
+            match state.check_move(&mv, player_pk) {
+                Ok(()) => break ChessCommand::Move(mv),
+                Err(err) => println!("Invalid move: {}", err),
+            }
+        };
+        let step = EpisodeMessage::<Chess>::new_signed_command(episode_id, cmd, sk, player_pk);
+
+        submitter.submit(&kaspad, &step).await.unwrap();
+
+        // Wait for current move, which hands the turn to the opponent, or for the game to end by resigning or by
+        // the opponent claiming a win on time if the move landed too late. Updates without metadata stem from a
+        // rejection or a reorg, after which the turn is re-evaluated
+        let side = state.position.side_to_move;
+        loop {
+            let Some(update) = response_receiver.recv().await else {
+                return;
+            };
+            (received_id, state, metadata) = update;
+            if received_id == episode_id
+                && (state.position.side_to_move != side
+                    || metadata.is_none()
+                    || !matches!(state.status, ChessGameStatus::InProgress(..)))
+            {
+                break;
+            }
+        }
+        print_update(&state, &metadata);
+    }
+}
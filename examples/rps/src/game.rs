@@ -0,0 +1,445 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::{
+    episode::{Episode, EpisodeError, PayloadMetadata},
+    pki::PubKey,
+};
+use log::info;
+use sha2::{Digest, Sha256};
+
+/// Largest supported number of rounds a player needs to win the match, i.e. a best-of-19
+pub const MAX_ROUNDS_TO_WIN: usize = 10;
+
+/// Number of DAA scores (roughly 10 minutes at 10 BPS) players have to commit or reveal before the
+/// opponent may claim the round
+pub const PHASE_TIMEOUT_DAA: u64 = 6000;
+
+/// Match rules, chosen by the creator of the episode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RpsConfig {
+    /// Number of rounds a player needs to win the match, clamped to `1..=MAX_ROUNDS_TO_WIN`
+    pub rounds_to_win: usize,
+}
+
+impl Default for RpsConfig {
+    /// Best-of-3 match
+    fn default() -> Self {
+        Self { rounds_to_win: 2 }
+    }
+}
+
+impl RpsConfig {
+    fn sanitized(self) -> Self {
+        Self { rounds_to_win: self.rounds_to_win.clamp(1, MAX_ROUNDS_TO_WIN) }
+    }
+}
+
+#[derive(Debug, BorshDeserialize, BorshSerialize)]
+pub enum RpsError {
+    AlreadyCommitted,
+    AlreadyRevealed,
+    NotRevealPhase,
+    CommitmentMismatch,
+    NoForfeit,
+    MatchOver,
+}
+
+impl std::fmt::Display for RpsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpsError::AlreadyCommitted => write!(f, "Player already committed a choice this round."),
+            RpsError::AlreadyRevealed => write!(f, "Player already revealed a choice this round."),
+            RpsError::NotRevealPhase => write!(f, "Choices can only be revealed once both players committed."),
+            RpsError::CommitmentMismatch => write!(f, "Revealed choice does not match the commitment."),
+            RpsError::NoForfeit => write!(f, "The opponent has not run out of time."),
+            RpsError::MatchOver => write!(f, "The match is already over."),
+        }
+    }
+}
+
+impl std::error::Error for RpsError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Choice {
+    Rock,
+    Paper,
+    Scissors,
+}
+
+impl Choice {
+    pub fn beats(self, other: Choice) -> bool {
+        matches!((self, other), (Choice::Rock, Choice::Scissors) | (Choice::Paper, Choice::Rock) | (Choice::Scissors, Choice::Paper))
+    }
+}
+
+pub type Salt = [u8; 32];
+pub type Commitment = [u8; 32];
+
+/// Hashes a choice with a secret salt. The player key is included so that an opponent cannot copy
+/// a commitment and later replay its reveal.
+pub fn commitment(player: PubKey, choice: Choice, salt: &Salt) -> Commitment {
+    let mut hasher = Sha256::new();
+    hasher.update(player.0.serialize());
+    hasher.update([choice as u8]);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
+pub enum RpsCommand {
+    Commit(Commitment),
+    Reveal {
+        choice: Choice,
+        salt: Salt,
+    },
+    /// Claims the round after the opponent failed to commit or reveal before the deadline
+    ClaimForfeit,
+    /// Concedes the match, handing it to the opponent
+    Resign,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RoundResult {
+    /// Revealed choices, missing for a player who forfeited
+    pub choices: [Option<Choice>; 2],
+    /// None for a tie
+    pub winner: Option<PubKey>,
+}
+
+/// The part of the match which commands modify, kept whole by rollbacks
+#[derive(Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MatchState {
+    commitments: [Option<Commitment>; 2],
+    choices: [Option<Choice>; 2],
+    results: Vec<RoundResult>,
+    deadline: u64,
+    resigned: Option<usize>,
+}
+
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct RpsRollback {
+    pub prev: MatchState,
+}
+
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct RpsState {
+    pub players: Vec<PubKey>,
+    pub config: RpsConfig,
+    pub committed: [bool; 2],
+    pub revealed: [bool; 2],
+    pub results: Vec<RoundResult>,
+    pub wins: [usize; 2],
+    /// DAA score after which a player still owing a commitment or reveal forfeits the round
+    pub deadline: u64,
+    pub winner: Option<PubKey>,
+    /// The player who conceded the match, if any
+    pub resigned: Option<PubKey>,
+}
+
+impl RpsState {
+    /// Whether the player at index `me` still owes a reveal in the current round, given the round of a reveal they sent
+    /// which is still pending, if any. A pending reveal of an earlier round does not count, since finishing a round resets
+    /// the reveals
+    pub fn owes_reveal(&self, me: usize, pending_reveal: Option<usize>) -> bool {
+        self.committed == [true, true] && !self.revealed[me] && pending_reveal != Some(self.results.len())
+    }
+
+    pub fn print(&self) {
+        if let Some(result) = self.results.last() {
+            let show = |c: Option<Choice>| c.map_or("(forfeit)".to_string(), |c| format!("{:?}", c));
+            println!("round {}: {} vs {}", self.results.len(), show(result.choices[0]), show(result.choices[1]));
+            match result.winner {
+                Some(pk) => println!("round winner: {}", pk),
+                None => println!("round tied"),
+            }
+        }
+        println!("score: {} - {} (first to {})", self.wins[0], self.wins[1], self.config.rounds_to_win);
+        if let Some(pk) = self.resigned {
+            println!("resigned: {}", pk);
+        }
+        if let Some(pk) = self.winner {
+            println!("match winner: {}", pk);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Rps {
+    pub(crate) players: Vec<PubKey>,
+    config: RpsConfig,
+    state: MatchState,
+}
+
+impl Episode for Rps {
+    type Command = RpsCommand;
+    type CommandRollback = RpsRollback;
+    type CommandError = RpsError;
+    type InitParams = RpsConfig;
+
+    fn initialize(participants: Vec<PubKey>, params: &RpsConfig, metadata: &PayloadMetadata) -> Self {
+        info!("[Rps] initialize: {:?}, {:?}", participants, params);
+        Self {
+            players: participants,
+            config: params.sanitized(),
+            state: MatchState { deadline: metadata.accepting_daa + PHASE_TIMEOUT_DAA, ..Default::default() },
+        }
+    }
+
+    fn execute(
+        &mut self,
+        cmd: &Self::Command,
+        authorization: Option<PubKey>,
+        metadata: &PayloadMetadata,
+    ) -> Result<Self::CommandRollback, EpisodeError<Self::CommandError>> {
+        let Some(player) = authorization else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        // Participants come straight from the episode tx, so a malformed match accepts no commands
+        let [p0, p1] = self.players[..] else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        if p0 == p1 {
+            return Err(EpisodeError::Unauthorized);
+        }
+        let Some(me) = self.players.iter().position(|&pk| pk == player) else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        if self.winner().is_some() {
+            return Err(EpisodeError::InvalidCommand(RpsError::MatchOver));
+        }
+
+        let prev = self.state.clone();
+        let state = &mut self.state;
+        let opponent = 1 - me;
+        match cmd {
+            RpsCommand::Commit(hash) => {
+                if state.commitments[me].is_some() {
+                    return Err(EpisodeError::InvalidCommand(RpsError::AlreadyCommitted));
+                }
+                state.commitments[me] = Some(*hash);
+                if state.commitments[opponent].is_some() {
+                    // The reveal phase gets a fresh deadline
+                    state.deadline = metadata.accepting_daa + PHASE_TIMEOUT_DAA;
+                }
+            }
+            RpsCommand::Reveal { choice, salt } => {
+                let [Some(_), Some(_)] = state.commitments else {
+                    return Err(EpisodeError::InvalidCommand(RpsError::NotRevealPhase));
+                };
+                if state.choices[me].is_some() {
+                    return Err(EpisodeError::InvalidCommand(RpsError::AlreadyRevealed));
+                }
+                if state.commitments[me] != Some(commitment(player, *choice, salt)) {
+                    return Err(EpisodeError::InvalidCommand(RpsError::CommitmentMismatch));
+                }
+                state.choices[me] = Some(*choice);
+                if let [Some(c0), Some(c1)] = state.choices {
+                    let winner = if c0.beats(c1) {
+                        Some(p0)
+                    } else if c1.beats(c0) {
+                        Some(p1)
+                    } else {
+                        None
+                    };
+                    self.finish_round(winner, metadata);
+                }
+            }
+            RpsCommand::ClaimForfeit => {
+                // The claimer must have completed the current phase while the opponent has not
+                let stalled = if state.commitments.iter().all(Option::is_some) {
+                    state.choices[me].is_some() && state.choices[opponent].is_none()
+                } else {
+                    state.commitments[me].is_some() && state.commitments[opponent].is_none()
+                };
+                if !stalled || metadata.accepting_daa <= state.deadline {
+                    return Err(EpisodeError::InvalidCommand(RpsError::NoForfeit));
+                }
+                self.finish_round(Some(player), metadata);
+            }
+            RpsCommand::Resign => state.resigned = Some(me),
+        }
+
+        info!("[Rps] execute: {:?}, {:?}", player, cmd);
+        Ok(RpsRollback { prev })
+    }
+
+    fn rollback(&mut self, rollback: RpsRollback) -> bool {
+        self.state = rollback.prev;
+        true
+    }
+}
+
+impl Rps {
+    pub fn poll(&self) -> RpsState {
+        RpsState {
+            players: self.players.clone(),
+            config: self.config,
+            committed: self.state.commitments.map(|c| c.is_some()),
+            revealed: self.state.choices.map(|c| c.is_some()),
+            results: self.state.results.clone(),
+            wins: self.wins(),
+            deadline: self.state.deadline,
+            winner: self.winner(),
+            resigned: self.state.resigned.and_then(|i| self.players.get(i).copied()),
+        }
+    }
+
+    fn finish_round(&mut self, winner: Option<PubKey>, metadata: &PayloadMetadata) {
+        let choices = self.state.choices;
+        self.state = MatchState {
+            results: std::mem::take(&mut self.state.results),
+            deadline: metadata.accepting_daa + PHASE_TIMEOUT_DAA,
+            ..Default::default()
+        };
+        self.state.results.push(RoundResult { choices, winner });
+    }
+
+    fn wins(&self) -> [usize; 2] {
+        let count =
+            |i: usize| self.state.results.iter().filter(|r| r.winner.is_some() && r.winner == self.players.get(i).copied()).count();
+        [count(0), count(1)]
+    }
+
+    fn winner(&self) -> Option<PubKey> {
+        if let Some(i) = self.state.resigned {
+            return self.players.iter().take(2).enumerate().find(|&(j, _)| j != i).map(|(_, &pk)| pk);
+        }
+        let wins = self.wins();
+        (0..2).find(|&i| wins[i] >= self.config.rounds_to_win).and_then(|i| self.players.get(i).copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kdapp::pki::generate_keypair;
+
+    fn metadata(daa: u64) -> PayloadMetadata {
+        PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: daa, accepting_time: 0, tx_id: 1u64.into() }
+    }
+
+    fn play_round(game: &mut Rps, choices: [Choice; 2]) {
+        let players = [game.players[0], game.players[1]];
+        for (i, choice) in choices.into_iter().enumerate() {
+            let cmd = RpsCommand::Commit(commitment(players[i], choice, &[i as u8; 32]));
+            game.execute(&cmd, Some(players[i]), &metadata(1)).unwrap();
+        }
+        for (i, choice) in choices.into_iter().enumerate() {
+            let cmd = RpsCommand::Reveal { choice, salt: [i as u8; 32] };
+            game.execute(&cmd, Some(players[i]), &metadata(1)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rps_match() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let mut game = Rps::initialize(vec![p1, p2], &RpsConfig::default(), &metadata(0));
+        play_round(&mut game, [Choice::Rock, Choice::Scissors]);
+        play_round(&mut game, [Choice::Rock, Choice::Rock]);
+        assert_eq!(game.poll().wins, [1, 0]);
+        play_round(&mut game, [Choice::Paper, Choice::Rock]);
+
+        let state = game.poll();
+        assert_eq!((state.results.len(), state.wins, state.winner), (3, [2, 0], Some(p1)));
+        let res = game.execute(&RpsCommand::Commit([0; 32]), Some(p2), &metadata(2));
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(RpsError::MatchOver))));
+
+        // A single round decides a best-of-1, while oversized params are clamped rather than trusted
+        let mut game = Rps::initialize(vec![p1, p2], &RpsConfig { rounds_to_win: 1 }, &metadata(0));
+        play_round(&mut game, [Choice::Scissors, Choice::Rock]);
+        assert_eq!(game.poll().winner, Some(p2));
+        let game = Rps::initialize(vec![p1, p2], &RpsConfig { rounds_to_win: usize::MAX }, &metadata(0));
+        assert_eq!(game.poll().config.rounds_to_win, MAX_ROUNDS_TO_WIN);
+        let game = Rps::initialize(vec![p1, p2], &RpsConfig { rounds_to_win: 0 }, &metadata(0));
+        assert_eq!(game.poll().config.rounds_to_win, 1);
+    }
+
+    #[test]
+    fn test_rps_pending_reveal() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let mut game = Rps::initialize(vec![p1, p2], &RpsConfig::default(), &metadata(0));
+        // Track the pending reveal of the second player, who reveals last in both rounds
+        let mut pending = None;
+        for (round, choices) in [[Choice::Rock, Choice::Paper], [Choice::Paper, Choice::Scissors]].into_iter().enumerate() {
+            for (i, (pk, choice)) in [p1, p2].into_iter().zip(choices).enumerate() {
+                game.execute(&RpsCommand::Commit(commitment(pk, choice, &[i as u8; 32])), Some(pk), &metadata(1)).unwrap();
+            }
+            assert!(game.poll().owes_reveal(1, pending));
+            pending = Some(round);
+            assert!(!game.poll().owes_reveal(1, pending));
+            for (i, (pk, choice)) in [p1, p2].into_iter().zip(choices).enumerate() {
+                game.execute(&RpsCommand::Reveal { choice, salt: [i as u8; 32] }, Some(pk), &metadata(1)).unwrap();
+            }
+            assert_eq!(game.poll().results.len(), round + 1);
+        }
+        assert_eq!(game.poll().winner, Some(p2));
+    }
+
+    #[test]
+    fn test_rps_commit_reveal() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let mut game = Rps::initialize(vec![p1, p2], &RpsConfig::default(), &metadata(0));
+        let salt = [7; 32];
+        let commit = RpsCommand::Commit(commitment(p1, Choice::Paper, &salt));
+        let snapshot = game.clone();
+        let rollback = game.execute(&commit, Some(p1), &metadata(1)).unwrap();
+        assert!(game.rollback(rollback));
+        assert_eq!(snapshot, game);
+
+        game.execute(&commit, Some(p1), &metadata(1)).unwrap();
+        let reveal = RpsCommand::Reveal { choice: Choice::Paper, salt };
+        assert!(matches!(game.execute(&reveal, Some(p1), &metadata(1)), Err(EpisodeError::InvalidCommand(RpsError::NotRevealPhase))));
+        // Copying the opponent's commitment does not allow replaying their reveal
+        game.execute(&commit, Some(p2), &metadata(1)).unwrap();
+        assert!(matches!(
+            game.execute(&reveal, Some(p2), &metadata(1)),
+            Err(EpisodeError::InvalidCommand(RpsError::CommitmentMismatch))
+        ));
+        let cheat = RpsCommand::Reveal { choice: Choice::Scissors, salt };
+        assert!(matches!(
+            game.execute(&cheat, Some(p1), &metadata(1)),
+            Err(EpisodeError::InvalidCommand(RpsError::CommitmentMismatch))
+        ));
+        game.execute(&reveal, Some(p1), &metadata(1)).unwrap();
+        assert_eq!(game.poll().revealed, [true, false]);
+    }
+
+    #[test]
+    fn test_rps_forfeit() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let mut game = Rps::initialize(vec![p1, p2], &RpsConfig::default(), &metadata(0));
+        game.execute(&RpsCommand::Commit(commitment(p1, Choice::Rock, &[0; 32])), Some(p1), &metadata(5)).unwrap();
+
+        let deadline = game.poll().deadline;
+        assert_eq!(deadline, PHASE_TIMEOUT_DAA);
+        let res = game.execute(&RpsCommand::ClaimForfeit, Some(p1), &metadata(deadline));
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(RpsError::NoForfeit))));
+        let res = game.execute(&RpsCommand::ClaimForfeit, Some(p2), &metadata(deadline + 1));
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(RpsError::NoForfeit))));
+
+        game.execute(&RpsCommand::ClaimForfeit, Some(p1), &metadata(deadline + 1)).unwrap();
+        let state = game.poll();
+        assert_eq!(state.results, vec![RoundResult { choices: [None, None], winner: Some(p1) }]);
+        assert_eq!((state.committed, state.deadline), ([false, false], deadline + 1 + PHASE_TIMEOUT_DAA));
+
+        // Resigning ends the match regardless of the score
+        let rollback = game.execute(&RpsCommand::Resign, Some(p1), &metadata(deadline + 2)).unwrap();
+        assert_eq!((game.poll().winner, game.poll().resigned), (Some(p2), Some(p1)));
+        let res = game.execute(&RpsCommand::Resign, Some(p2), &metadata(deadline + 2));
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(RpsError::MatchOver))));
+        assert!(game.rollback(rollback));
+        assert_eq!(game.poll().winner, None);
+    }
+
+    #[test]
+    fn test_rps_malformed_players() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        for players in [vec![p1], vec![p1, p1], vec![p1, p2, p1]] {
+            let mut game = Rps::initialize(players, &RpsConfig::default(), &metadata(0));
+            for cmd in [RpsCommand::Resign, RpsCommand::Commit([0; 32]), RpsCommand::ClaimForfeit] {
+                assert!(matches!(game.execute(&cmd, Some(p1), &metadata(1)), Err(EpisodeError::Unauthorized)));
+            }
+            let state = game.poll();
+            assert_eq!((state.winner, state.resigned), (None, None));
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use clap::Parser;
+use kaspa_addresses::{Address, Version};
+use kaspa_wrpc_client::prelude::*;
+use log::*;
+use rand::Rng;
+use secp256k1::{Keypair, PublicKey, SecretKey};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use kdapp::{
+    engine::{self, EngineConfig, EpisodeMessage},
+    episode::{EpisodeError, EpisodeEventHandler, EpisodeId, PayloadMetadata},
+    generator::{self, PatternType, PrefixType},
+    pki::{generate_keypair, PubKey},
+    proxy::{self, connect_client, connect_client_with_failover, CommandSubmitter},
+    utils::{self, network::NetworkProfile},
+};
+
+use game::{Choice, Rps, RpsCommand, RpsConfig, RpsState};
+
+pub mod game;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Kaspa schnorr private key
+    #[arg(short, long)]
+    kaspa_private_key: Option<String>,
+
+    /// Game private key
+    #[arg(short = 'g', long)]
+    game_private_key: Option<String>,
+
+    /// Game opponent public key
+    #[arg(short = 'o', long)]
+    game_opponent_key: Option<String>,
+
+    /// Number of rounds a player needs to win the match, when initiating the game. Defaults to a best-of-3
+    #[arg(long, default_value_t = 2)]
+    rounds_to_win: usize,
+
+    /// Indicates whether to run the interaction over mainnet (default: testnet 10)
+    #[arg(short, long, default_value_t = false)]
+    mainnet: bool,
+
+    /// Fee in sompi attached to each game transaction. Defaults to the default fee of the network
+    #[arg(long)]
+    fee: Option<u64>,
+
+    /// Specifies the wRPC Kaspa Node URL to use. Usage: <wss://localhost>. Defaults to the Public Node Network (PNN).
+    /// May be repeated, in which case the first responsive node is used
+    #[arg(short, long)]
+    wrpc_url: Vec<String>,
+
+    /// Logging level for all subsystems {off, error, warn, info, debug, trace}
+    ///  -- You may also specify `<subsystem>=<level>,<subsystem2>=<level>,...` to set the log level for individual subsystems
+    #[arg(long = "loglevel", default_value = format!("info,{}=trace", env!("CARGO_PKG_NAME")))]
+    log_level: String,
+}
+
+#[tokio::main]
+async fn main() {
+    // Get CLI arguments
+    let args = Args::parse();
+
+    // Init logger
+    kaspa_core::log::init_logger(None, &args.log_level);
+
+    // Select network
+    let profile = if args.mainnet { NetworkProfile::MAINNET } else { NetworkProfile::TESTNET_10 };
+    let (network, prefix) = (profile.network_id, profile.prefix());
+
+    // Generate or obtain Kaspa private key
+    let kaspa_signer = if let Some(private_key_hex) = args.kaspa_private_key {
+        let mut private_key_bytes = [0u8; 32];
+        faster_hex::hex_decode(private_key_hex.as_bytes(), &mut private_key_bytes).unwrap();
+        Keypair::from_seckey_slice(secp256k1::SECP256K1, &private_key_bytes).unwrap()
+    } else {
+        let (sk, pk) = &secp256k1::generate_keypair(&mut rand::thread_rng());
+        info!(
+            "Generated private key {} and address {}. Send some funds to this address and rerun with `--kaspa-private-key {}`",
+            sk.display_secret(),
+            String::from(&Address::new(prefix, Version::PubKey, &pk.x_only_public_key().0.serialize())),
+            sk.display_secret()
+        );
+        if let Some(faucet_url) = profile.faucet_url {
+            info!("Testnet funds are available from the faucet at {}", faucet_url);
+        }
+        return;
+    };
+
+    // Extract Kaspa address
+    let kaspa_addr = Address::new(prefix, Version::PubKey, &kaspa_signer.x_only_public_key().0.serialize());
+
+    // Obtain game keys
+    let (sk, player_pk) = if let Some(game_key_hex) = args.game_private_key {
+        let pair = Keypair::from_str(&game_key_hex).unwrap();
+        (pair.secret_key(), PubKey(pair.public_key()))
+    } else {
+        let (sk, pk) = generate_keypair();
+        info!("Player private key: {}", sk.display_secret());
+        (sk, pk)
+    };
+
+    info!("Player public key: {}", player_pk);
+
+    // ... and opponent pk
+    let opponent_pk = args.game_opponent_key.map(|opponent_key_hex| PubKey(PublicKey::from_str(&opponent_key_hex).unwrap()));
+    let game_config = RpsConfig { rounds_to_win: args.rounds_to_win };
+
+    // Connect kaspad clients
+    let kaspad = connect_client_with_failover(network, &args.wrpc_url).await.unwrap();
+    let player_kaspad = connect_client(network, kaspad.url()).await.unwrap(); // Pin to the same node
+
+    // Define channels and exit flag
+    let (sender, receiver) = channel();
+    let (response_sender, response_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let exit_signal = Arc::new(AtomicBool::new(false));
+    let exit_signal_receiver = exit_signal.clone();
+
+    // On Ctrl-C or SIGTERM, stop the listener which in turn signals the engine to exit
+    let interrupted = proxy::exit_on_ctrl_c(exit_signal.clone());
+
+    // Keep episodes for as long as their txs are retained by the node
+    let episode_lifetime = proxy::pruning_window(&kaspad).await.unwrap();
+    info!("Episode lifetime: {} DAA score units", episode_lifetime);
+
+    // Run the engine
+    let config = EngineConfig { episode_lifetime, ..Default::default() };
+    let mut engine = engine::Engine::<Rps, RpsHandler>::with_config(receiver, config);
+    let engine_task = tokio::task::spawn_blocking(move || {
+        engine.start(vec![RpsHandler { sender: response_sender, player: player_pk }]);
+    });
+
+    // Run the player task
+    let fee = args.fee.unwrap_or(profile.default_fee);
+    let generator = generator::TransactionGenerator::new(kaspa_signer, PATTERN, PREFIX);
+    let submitter = CommandSubmitter::new(&player_kaspad, generator, kaspa_addr, profile, fee, opponent_pk.is_some()).await.unwrap();
+    let player_task = tokio::spawn(async move {
+        play_rps(player_kaspad, submitter, response_receiver, exit_signal, sk, player_pk, opponent_pk, game_config).await;
+    });
+
+    // Run the kaspad listener
+    let engines = std::iter::once((PREFIX, (PATTERN, sender))).collect();
+    proxy::run_listener(kaspad, network, args.wrpc_url, engines, exit_signal_receiver).await;
+
+    engine_task.await.unwrap();
+    if interrupted.load(Ordering::Relaxed) {
+        // The player task might be blocked on stdin, so exit without awaiting it
+        std::process::exit(0);
+    }
+    player_task.await.unwrap();
+}
+
+const PREFIX: PrefixType = 1380995923;
+const PATTERN: PatternType = generator::pattern_from_prefix(PREFIX);
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A game state together with the metadata of the accepted transaction which produced it, if any
+type StateUpdate = (EpisodeId, RpsState, Option<PayloadMetadata>);
+
+struct RpsHandler {
+    sender: UnboundedSender<StateUpdate>,
+    player: PubKey, // The local player pubkey
+}
+
+impl EpisodeEventHandler<Rps> for RpsHandler {
+    fn on_initialize(&self, episode_id: kdapp::episode::EpisodeId, episode: &Rps) {
+        if episode.players.contains(&self.player) {
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+
+    fn on_command(
+        &self,
+        episode_id: kdapp::episode::EpisodeId,
+        episode: &Rps,
+        _cmd: &<Rps as kdapp::episode::Episode>::Command,
+        _authorization: Option<PubKey>,
+        metadata: &kdapp::episode::PayloadMetadata,
+    ) {
+        if episode.players.contains(&self.player) {
+            let _ = self.sender.send((episode_id, episode.poll(), Some(metadata.clone())));
+        }
+    }
+
+    fn on_rollback(&self, episode_id: kdapp::episode::EpisodeId, episode: &Rps, metadata: &kdapp::episode::PayloadMetadata) {
+        if episode.players.contains(&self.player) {
+            warn!("A DAG reorg reverted tx {} in episode {}", metadata.tx_id, episode_id);
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+
+    fn on_reject(
+        &self,
+        episode_id: kdapp::episode::EpisodeId,
+        episode: &Rps,
+        cmd: &<Rps as kdapp::episode::Episode>::Command,
+        authorization: Option<PubKey>,
+        error: &EpisodeError<<Rps as kdapp::episode::Episode>::CommandError>,
+        _metadata: &kdapp::episode::PayloadMetadata,
+    ) {
+        if authorization == Some(self.player) && error.is_authenticated() {
+            println!("Your command {:?} was rejected: {}", cmd, error);
+            // Wake the player task, which would otherwise keep waiting for the command to take effect
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+}
+
+/// Prints a game state along with when and where the transaction producing it was accepted
+fn print_update(state: &RpsState, metadata: &Option<PayloadMetadata>) {
+    state.print();
+    if let Some(metadata) = metadata {
+        println!("{}", utils::format_acceptance(metadata));
+    }
+}
+
+async fn play_rps(
+    kaspad: KaspaRpcClient,
+    mut submitter: CommandSubmitter,
+    mut response_receiver: UnboundedReceiver<StateUpdate>,
+    exit_signal: Arc<AtomicBool>,
+    sk: SecretKey,
+    player_pk: PubKey,
+    opponent_pk: Option<PubKey>,
+    config: RpsConfig,
+) {
+    // When opponent pk is passed, we are expected to initiate the game
+    if let Some(opponent_pk) = opponent_pk {
+        let episode_id = rand::thread_rng().gen();
+        let new_episode = EpisodeMessage::<Rps>::NewEpisode { episode_id, participants: vec![player_pk, opponent_pk], params: config };
+        submitter.submit(&kaspad, &new_episode).await.unwrap();
+    }
+
+    // Participants come straight from the episode tx, so wait for a match between us and a distinct opponent. A closed
+    // channel means the engine has exited
+    let (episode_id, mut state, mut metadata, me) = loop {
+        let Some((episode_id, state, metadata)) = response_receiver.recv().await else {
+            return;
+        };
+        let distinct_pair = matches!(state.players[..], [p0, p1] if p0 != p1);
+        match state.players.iter().position(|&pk| pk == player_pk) {
+            Some(me) if distinct_pair => break (episode_id, state, metadata, me),
+            _ => warn!("Skipping episode {} with malformed players {:?}", episode_id, state.players),
+        }
+    };
+    print_update(&state, &metadata);
+
+    let opponent = 1 - me;
+    let mut input = String::new();
+    // The secret choice of the current round, kept until its reveal is accepted
+    let mut secret: Option<(usize, Choice, game::Salt)> = None;
+    // The round of a reveal which was submitted and neither accepted nor reverted yet
+    let mut pending_reveal: Option<usize> = None;
+    let mut claimed = false;
+
+    while state.winner.is_none() {
+        let round = state.results.len();
+        if state.revealed[me] || secret.is_some_and(|(r, ..)| r != round) {
+            secret = None;
+        }
+        let command = if !state.committed[me] && secret.is_none() {
+            // None stands for resigning
+            let choice = loop {
+                input.clear();
+                println!(
+                    "Round {}, choose: [r]ock, [p]aper or [s]cissors, or `resign` (before DAA score {})",
+                    round + 1,
+                    state.deadline
+                );
+                std::io::stdin().read_line(&mut input).unwrap();
+                match input.trim() {
+                    "r" | "rock" => break Some(Choice::Rock),
+                    "p" | "paper" => break Some(Choice::Paper),
+                    "s" | "scissors" => break Some(Choice::Scissors),
+                    "resign" => break None,
+                    _ => println!("Invalid input, expected: r, p, s or resign"),
+                }
+            };
+            match choice {
+                Some(choice) => {
+                    let salt = rand::thread_rng().gen();
+                    secret = Some((round, choice, salt));
+                    Some(RpsCommand::Commit(game::commitment(player_pk, choice, &salt)))
+                }
+                None => Some(RpsCommand::Resign),
+            }
+        } else if state.owes_reveal(me, pending_reveal) {
+            // Reveal as soon as the opponent is committed as well
+            pending_reveal = Some(round);
+            secret.map(|(_, choice, salt)| RpsCommand::Reveal { choice, salt })
+        } else {
+            None
+        };
+        if let Some(cmd) = command {
+            let step = EpisodeMessage::<Rps>::new_signed_command(episode_id, cmd, sk, player_pk);
+            submitter.submit(&kaspad, &step).await.unwrap();
+        }
+
+        // Wait for the next update of this episode, claiming the round if the opponent stalls past the deadline
+        loop {
+            let update = tokio::select! {
+                update = response_receiver.recv() => update,
+                _ = tokio::time::sleep(DEADLINE_POLL_INTERVAL) => {
+                    let stalled = if state.committed == [true, true] {
+                        state.revealed[me] && !state.revealed[opponent]
+                    } else {
+                        state.committed[me] && !state.committed[opponent]
+                    };
+                    if stalled && !claimed && proxy::virtual_daa_score(&kaspad).await.is_some_and(|daa| daa > state.deadline) {
+                        println!("Opponent ran out of time, claiming the round");
+                        let step = EpisodeMessage::<Rps>::new_signed_command(episode_id, RpsCommand::ClaimForfeit, sk, player_pk);
+                        submitter.submit(&kaspad, &step).await.unwrap();
+                        claimed = true;
+                    }
+                    continue;
+                }
+            };
+            let Some((received_id, new_state, new_metadata)) = update else {
+                return;
+            };
+            if received_id == episode_id {
+                (state, metadata) = (new_state, new_metadata);
+                claimed = false;
+                // Updates without metadata come from reorgs and rejections, after which a pending reveal is resubmitted
+                if metadata.is_none() {
+                    pending_reveal = None;
+                }
+                break;
+            }
+        }
+        print_update(&state, &metadata);
+    }
+    exit_signal.store(true, Ordering::Relaxed);
+}
@@ -14,6 +14,9 @@ pub enum TTTError {
     GameOver,
     NoNewPlayers,
     Unauthorized,
+    NoTimeout,
+    NotLowestCell,
+    NotTwoPlayers,
 }
 
 impl std::fmt::Display for TTTError {
@@ -25,6 +28,9 @@ impl std::fmt::Display for TTTError {
             TTTError::GameOver => write!(f, "The game is already over."),
             TTTError::NoNewPlayers => write!(f, "Tic-tac-toe does not allow addition of new players."),
             TTTError::Unauthorized => write!(f, "Unauthorized participant."),
+            TTTError::NoTimeout => write!(f, "The opponent has not run out of time."),
+            TTTError::NotLowestCell => write!(f, "Cell is not the lowest free one in its column."),
+            TTTError::NotTwoPlayers => write!(f, "Only two-player games can be ended this way."),
         }
     }
 }
@@ -38,96 +44,188 @@ pub struct TTTMove {
 }
 
 #[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
-pub struct TTTRollback {
-    pub mv: TTTMove,
-    pub removed_mv: Option<TTTMove>,
-    pub prev_timestamp: u64,
+pub enum TTTCommand {
+    Move(TTTMove),
+    /// Claims the win after the opponent let their turn deadline pass. Two-player games only
+    ClaimTimeout,
+    /// Concedes the game, which either player may do at any time while it is in progress. Two-player games only
+    Resign,
 }
 
-impl TTTRollback {
-    pub fn new(mv: TTTMove, removed_mv: Option<TTTMove>, prev_timestamp: u64) -> Self {
-        Self { mv, removed_mv, prev_timestamp }
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
+pub enum TTTRollback {
+    Move { mv: TTTMove, removed_mv: Option<TTTMove>, prev_timestamp: u64, prev_turn_daa: u64 },
+    ClaimTimeout,
+    Resign,
+}
+
+/// Largest supported board side, bounding the memory a new episode message can make the engine allocate
+pub const MAX_BOARD_SIZE: usize = 19;
+
+/// Default number of DAA scores (roughly 10 minutes at 10 BPS) a player has to move before the opponent may claim the win
+pub const DEFAULT_TURN_TIMEOUT_DAA: u64 = 6000;
+
+/// Shortest and longest supported turn timeouts, roughly a minute and a day at 10 BPS
+pub const MIN_TURN_TIMEOUT_DAA: u64 = 600;
+pub const MAX_TURN_TIMEOUT_DAA: u64 = 864_000;
+
+/// Symbols marking the cells of each player, by participant order
+const SYMBOLS: &[u8] = b"XOABCDEFGHIJKLMNPQRSTUVWYZ";
+
+/// Largest supported number of players, each marking cells by a distinct symbol
+pub const MAX_PLAYERS: usize = SYMBOLS.len();
+
+/// Board dimensions and rules, chosen by the creator of the episode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct TTTConfig {
+    /// Side length of the square board, clamped to `1..=MAX_BOARD_SIZE`
+    pub size: usize,
+    /// Number of symbols in a row, column or diagonal needed to win, clamped to `1..=size`
+    pub win_length: usize,
+    /// When set, each player's move beyond this many symbols on board removes the oldest symbol. Unset by the gravity
+    /// variant, where a removal could leave symbols floating above an empty cell
+    pub max_symbols: Option<usize>,
+    /// Misère variant: completing a line loses the game instead of winning it
+    pub misere: bool,
+    /// Gravity variant: symbols must be placed on the lowest free cell of a column, as in connect four
+    pub gravity: bool,
+    /// Number of DAA scores a player has to move before the opponent may claim the win, clamped to `MIN_TURN_TIMEOUT_DAA..=MAX_TURN_TIMEOUT_DAA`
+    pub turn_timeout: u64,
+}
+
+impl Default for TTTConfig {
+    /// Classic 3x3 board, where each move beyond six symbols removes the oldest one
+    fn default() -> Self {
+        Self { size: 3, win_length: 3, max_symbols: Some(6), misere: false, gravity: false, turn_timeout: DEFAULT_TURN_TIMEOUT_DAA }
     }
 }
 
+impl TTTConfig {
+    fn sanitized(self) -> Self {
+        let size = self.size.clamp(1, MAX_BOARD_SIZE);
+        Self {
+            size,
+            win_length: self.win_length.clamp(1, size),
+            max_symbols: self.max_symbols.filter(|&max| max > 0 && !self.gravity),
+            turn_timeout: self.turn_timeout.clamp(MIN_TURN_TIMEOUT_DAA, MAX_TURN_TIMEOUT_DAA),
+            ..self
+        }
+    }
+}
+
+pub type TTTBoard = Vec<Vec<Option<PubKey>>>;
+
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
 pub struct TTTState {
-    pub board: [[Option<PubKey>; 3]; 3],
-    pub first_player: PubKey,
+    pub board: TTTBoard,
+    pub players: Vec<PubKey>,
+    pub config: TTTConfig,
     pub status: TTTGameStatus,
+    /// DAA score after which the player to move may be claimed out of time
+    pub turn_deadline: u64,
 }
 
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
 pub enum TTTGameStatus {
     InProgress(PubKey),
     Winner(PubKey),
+    WonOnTime(PubKey),
+    /// Ended by the given player completing a line in the misère variant
+    Loser(PubKey),
+    /// Ended by the given player conceding, handing the win to the opponent
+    Resigned(PubKey),
     Draw,
+    /// The episode was not created with `2..=MAX_PLAYERS` distinct participants, so it accepts no commands
+    Invalid,
 }
 
 impl TTTState {
+    /// Checks a move by `player` against this state, allowing invalid moves to be rejected locally
+    /// before paying the fee of a transaction the engine would reject anyway
+    pub fn check_move(&self, mv: &TTTMove, player: PubKey) -> Result<(), TTTError> {
+        match self.status {
+            TTTGameStatus::InProgress(pk) if pk != player => return Err(TTTError::NotPlayersTurn),
+            TTTGameStatus::InProgress(_) => {}
+            _ => return Err(TTTError::GameOver),
+        }
+        if mv.row >= self.config.size || mv.col >= self.config.size {
+            return Err(TTTError::OutOfBounds);
+        }
+        if self.board[mv.row][mv.col].is_some() {
+            return Err(TTTError::Occupied);
+        }
+        if self.config.gravity && self.board[mv.row + 1..].iter().any(|row| row[mv.col].is_none()) {
+            return Err(TTTError::NotLowestCell);
+        }
+        Ok(())
+    }
+
+    pub fn symbol(&self, player: PubKey) -> char {
+        let index = self.players.iter().position(|&pk| pk == player).unwrap_or_default();
+        SYMBOLS[index % SYMBOLS.len()] as char
+    }
+
     pub fn print(&self) {
-        Self::print_board(&self.board, self.first_player);
+        self.print_board();
         match self.status {
             TTTGameStatus::InProgress(_pk) => {}
-            TTTGameStatus::Winner(pk) => println!("winner: {} [{}]", if pk == self.first_player { "X" } else { "O" }, pk),
+            TTTGameStatus::Winner(pk) => println!("winner: {} [{}]", self.symbol(pk), pk),
+            TTTGameStatus::WonOnTime(pk) => println!("winner on time: {} [{}]", self.symbol(pk), pk),
+            TTTGameStatus::Loser(pk) => println!("loser: {} [{}]", self.symbol(pk), pk),
+            TTTGameStatus::Resigned(pk) => println!("resigned: {} [{}]", self.symbol(pk), pk),
             TTTGameStatus::Draw => println!("---- Draw ----"),
+            TTTGameStatus::Invalid => println!("---- Invalid episode ----"),
         }
     }
 
-    fn print_board(board: &[[Option<PubKey>; 3]; 3], p1: PubKey) {
-        // Iterate over each row with its index (0, 1, or 2)
-        for (row_index, row) in board.iter().enumerate() {
-            // Iterate over each cell in the row with its index
-            for (col_index, cell) in row.iter().enumerate() {
-                // Use a match statement to determine which character to print
-                let symbol = match cell {
-                    Some(p) if *p == p1 => "X",
-                    Some(_) => "O",
-                    None => " ",
-                };
-
-                // Print the symbol with padding for nice spacing
-                print!(" {} ", symbol);
-
-                // Print a vertical separator between cells, but not after the last one
-                if col_index < 2 {
-                    print!("|");
-                }
-            }
-
-            // After printing all cells in a row, move to the next line
-            println!();
+    fn print_board(&self) {
+        let size = self.board.len();
+        // Iterate over each row with its index
+        for (row_index, row) in self.board.iter().enumerate() {
+            // Print each cell's symbol with padding for nice spacing, separated by vertical bars
+            let cells: Vec<String> = row.iter().map(|cell| format!(" {} ", cell.map_or(' ', |p| self.symbol(p)))).collect();
+            println!("{}", cells.join("|"));
 
             // Print a horizontal separator between rows, but not after the last one
-            if row_index < 2 {
-                println!("---+---+---");
+            if row_index < size - 1 {
+                println!("{}", vec!["---"; size].join("+"));
             }
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
 pub struct TicTacToe {
-    pub(crate) board: [[Option<PubKey>; 3]; 3],
+    pub(crate) board: TTTBoard,
     pub(crate) players: Vec<PubKey>,
+    config: TTTConfig,
     current_index: usize,
     timestamp: u64,
     move_history: VecDeque<(usize, usize)>,
+    turn_daa: u64,
+    timeout_winner: Option<PubKey>,
+    resigned: Option<PubKey>,
 }
 
 impl Episode for TicTacToe {
-    type Command = TTTMove;
+    type Command = TTTCommand;
     type CommandRollback = TTTRollback;
     type CommandError = TTTError;
+    type InitParams = TTTConfig;
 
-    fn initialize(participants: Vec<PubKey>, metadata: &PayloadMetadata) -> Self {
-        info!("[TicTacToe] initialize: {:?}", participants);
+    fn initialize(participants: Vec<PubKey>, params: &TTTConfig, metadata: &PayloadMetadata) -> Self {
+        let config = params.sanitized();
+        info!("[TicTacToe] initialize: {:?}, {:?}", participants, config);
         Self {
-            board: [[None; 3]; 3],
+            board: vec![vec![None; config.size]; config.size],
             players: participants,
+            config,
             current_index: 0,
             timestamp: metadata.accepting_time,
             move_history: VecDeque::new(),
+            turn_daa: metadata.accepting_daa,
+            timeout_winner: None,
+            resigned: None,
         }
     }
 
@@ -140,91 +238,152 @@ impl Episode for TicTacToe {
         let Some(player) = authorization else {
             return Err(EpisodeError::Unauthorized);
         };
-        if player != self.players[self.current_index] {
-            return Err(EpisodeError::InvalidCommand(TTTError::NotPlayersTurn));
+        if !self.has_valid_players() || !self.players.contains(&player) {
+            return Err(EpisodeError::Unauthorized);
+        }
+        match cmd {
+            TTTCommand::Move(mv) => self.execute_move(mv, player, metadata),
+            TTTCommand::ClaimTimeout => self.execute_claim_timeout(player, metadata),
+            TTTCommand::Resign => self.execute_resign(player),
         }
-        if cmd.row >= 3 || cmd.col >= 3 {
-            return Err(EpisodeError::InvalidCommand(TTTError::OutOfBounds));
+    }
+
+    fn rollback(&mut self, rollback: TTTRollback) -> bool {
+        match rollback {
+            TTTRollback::Move { mv, removed_mv, prev_timestamp, prev_turn_daa } => {
+                if self.board[mv.row][mv.col].is_none() {
+                    return false;
+                }
+                self.timestamp = prev_timestamp;
+                self.turn_daa = prev_turn_daa;
+                self.board[mv.row][mv.col] = None;
+                let n = self.players.len();
+                self.current_index = (self.current_index + n - 1) % n;
+                self.move_history.pop_back();
+                // Restore removed cell
+                if let (Some(removed_mv), Some(max)) = (removed_mv, self.config.max_symbols) {
+                    // Turns strictly rotate, so the removed symbol was placed `max` moves before this one
+                    let owner = (self.current_index + n - max % n) % n;
+                    self.board[removed_mv.row][removed_mv.col] = Some(self.players[owner]);
+                    self.move_history.push_front((removed_mv.row, removed_mv.col));
+                }
+                true
+            }
+            TTTRollback::ClaimTimeout => self.timeout_winner.take().is_some(),
+            TTTRollback::Resign => self.resigned.take().is_some(),
         }
+    }
+}
 
-        if self.board[cmd.row][cmd.col].is_some() {
-            return Err(EpisodeError::InvalidCommand(TTTError::Occupied));
+impl TicTacToe {
+    pub fn poll(&self) -> TTTState {
+        TTTState {
+            board: self.board.clone(),
+            players: self.players.clone(),
+            config: self.config,
+            status: if !self.has_valid_players() {
+                TTTGameStatus::Invalid
+            } else if let Some(winner) = self.timeout_winner {
+                TTTGameStatus::WonOnTime(winner)
+            } else if let Some(player) = self.resigned {
+                TTTGameStatus::Resigned(player)
+            } else if let Some(player) = self.check_winner() {
+                if self.config.misere {
+                    TTTGameStatus::Loser(player)
+                } else {
+                    TTTGameStatus::Winner(player)
+                }
+            } else if self.is_draw() {
+                TTTGameStatus::Draw
+            } else {
+                TTTGameStatus::InProgress(self.players[self.current_index])
+            },
+            turn_deadline: self.turn_daa + self.config.turn_timeout,
         }
+    }
 
-        info!("[TicTacToe] execute: {:?}, {:?}", player, cmd);
+    fn execute_move(
+        &mut self,
+        mv: &TTTMove,
+        player: PubKey,
+        metadata: &PayloadMetadata,
+    ) -> Result<TTTRollback, EpisodeError<TTTError>> {
+        self.poll().check_move(mv, player).map_err(EpisodeError::InvalidCommand)?;
+
+        info!("[TicTacToe] execute: {:?}, {:?}", player, mv);
 
         let mut removed_mv = None;
 
-        // Enforce maximum 6 symbols
-        if self.move_history.len() == 6 {
+        // Enforce the maximum number of symbols on board
+        if self.config.max_symbols.is_some_and(|max| self.move_history.len() == max) {
             if let Some((old_row, old_col)) = self.move_history.pop_front() {
                 self.board[old_row][old_col] = None;
                 removed_mv = Some(TTTMove { row: old_row, col: old_col });
             }
         }
 
-        self.board[cmd.row][cmd.col] = Some(player);
-        self.move_history.push_back((cmd.row, cmd.col));
+        self.board[mv.row][mv.col] = Some(player);
+        self.move_history.push_back((mv.row, mv.col));
 
         let old_timestamp = self.timestamp;
         self.timestamp = metadata.accepting_time;
+        let prev_turn_daa = self.turn_daa;
+        self.turn_daa = metadata.accepting_daa;
 
         self.current_index = (self.current_index + 1) % self.players.len();
 
-        Ok(TTTRollback::new(*cmd, removed_mv, old_timestamp))
+        Ok(TTTRollback::Move { mv: *mv, removed_mv, prev_timestamp: old_timestamp, prev_turn_daa })
     }
 
-    fn rollback(&mut self, rollback: TTTRollback) -> bool {
-        if self.board[rollback.mv.row][rollback.mv.col].is_none() {
-            return false;
+    fn execute_claim_timeout(&mut self, player: PubKey, metadata: &PayloadMetadata) -> Result<TTTRollback, EpisodeError<TTTError>> {
+        if self.players.len() != 2 {
+            return Err(EpisodeError::InvalidCommand(TTTError::NotTwoPlayers));
         }
-        self.timestamp = rollback.prev_timestamp;
-        self.board[rollback.mv.row][rollback.mv.col] = None;
-        self.current_index = (self.current_index + 1) % self.players.len();
-        self.move_history.pop_back();
-        // Restore removed cell
-        if let Some(removed_mv) = rollback.removed_mv {
-            // 6 moves back is always current player
-            self.board[removed_mv.row][removed_mv.col] = Some(self.players[self.current_index]);
-            self.move_history.push_front((removed_mv.row, removed_mv.col));
+        let state = self.poll();
+        match state.status {
+            TTTGameStatus::InProgress(pk) if pk != player && metadata.accepting_daa > state.turn_deadline => {}
+            TTTGameStatus::InProgress(_) => return Err(EpisodeError::InvalidCommand(TTTError::NoTimeout)),
+            _ => return Err(EpisodeError::InvalidCommand(TTTError::GameOver)),
         }
-        true
+        info!("[TicTacToe] timeout claimed by {:?}", player);
+        self.timeout_winner = Some(player);
+        Ok(TTTRollback::ClaimTimeout)
     }
-}
 
-impl TicTacToe {
-    pub fn poll(&self) -> TTTState {
-        TTTState {
-            board: self.board,
-            first_player: self.players[0],
-            status: if let Some(winner) = self.check_winner() {
-                TTTGameStatus::Winner(winner)
-            } else if self.is_draw() {
-                TTTGameStatus::Draw
-            } else {
-                TTTGameStatus::InProgress(self.players[self.current_index])
-            },
+    fn execute_resign(&mut self, player: PubKey) -> Result<TTTRollback, EpisodeError<TTTError>> {
+        if self.players.len() != 2 {
+            return Err(EpisodeError::InvalidCommand(TTTError::NotTwoPlayers));
         }
+        if !matches!(self.poll().status, TTTGameStatus::InProgress(_)) {
+            return Err(EpisodeError::InvalidCommand(TTTError::GameOver));
+        }
+        info!("[TicTacToe] resigned by {:?}", player);
+        self.resigned = Some(player);
+        Ok(TTTRollback::Resign)
+    }
+
+    /// Participants come straight from the episode tx, so their number and uniqueness are checked before use
+    fn has_valid_players(&self) -> bool {
+        let players = &self.players;
+        (2..=MAX_PLAYERS).contains(&players.len()) && players.iter().enumerate().all(|(i, pk)| !players[..i].contains(pk))
     }
 
     fn check_winner(&self) -> Option<PubKey> {
-        let b = &self.board;
-        let lines = [
-            [(0, 0), (0, 1), (0, 2)],
-            [(1, 0), (1, 1), (1, 2)],
-            [(2, 0), (2, 1), (2, 2)],
-            [(0, 0), (1, 0), (2, 0)],
-            [(0, 1), (1, 1), (2, 1)],
-            [(0, 2), (1, 2), (2, 2)],
-            [(0, 0), (1, 1), (2, 2)],
-            [(0, 2), (1, 1), (2, 0)],
-        ];
-
-        for line in lines.iter() {
-            let [(r1, c1), (r2, c2), (r3, c3)] = line;
-            if let (Some(p1), Some(p2), Some(p3)) = (b[*r1][*c1], b[*r2][*c2], b[*r3][*c3]) {
-                if p1 == p2 && p2 == p3 {
-                    return Some(p1);
+        let (size, k) = (self.config.size, self.config.win_length);
+        // Scan from every cell rightwards, downwards and along both diagonals
+        let directions: [(usize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+        for row in 0..size {
+            for col in 0..size {
+                let Some(p) = self.board[row][col] else {
+                    continue;
+                };
+                for (dr, dc) in directions {
+                    let line = (1..k).map(|i| (row + i * dr, col as isize + i as isize * dc));
+                    if line.clone().all(|(r, c)| r < size && (0..size as isize).contains(&c))
+                        && line.into_iter().all(|(r, c)| self.board[r][c as usize] == Some(p))
+                    {
+                        return Some(p);
+                    }
                 }
             }
         }
@@ -239,29 +398,84 @@ impl TicTacToe {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use kaspa_addresses::{Address, Prefix, Version};
+    use kaspa_consensus_core::{
+        tx::{TransactionOutpoint, UtxoEntry},
+        Hash,
+    };
+    use kaspa_txscript::pay_to_address_script;
+    use kdapp::episode::{EpisodeEventHandler, EpisodeId};
     use kdapp::{
-        engine::{self, EngineMsg as Msg, EpisodeMessage},
+        engine::{self, EngineConfig, EngineMsg as Msg, EpisodeMessage, SnapshotError},
+        generator::{self, Payload, PrefixType},
         pki::{generate_keypair, sign_message, to_message},
     };
 
+    #[derive(Default)]
+    struct ExpireHandler(std::cell::RefCell<Vec<EpisodeId>>);
+
+    impl EpisodeEventHandler<TicTacToe> for ExpireHandler {
+        fn on_initialize(&self, _episode_id: EpisodeId, _episode: &TicTacToe) {}
+        fn on_command(&self, _: EpisodeId, _: &TicTacToe, _: &TTTCommand, _: Option<PubKey>, _: &PayloadMetadata) {}
+        fn on_rollback(&self, _episode_id: EpisodeId, _episode: &TicTacToe, _metadata: &PayloadMetadata) {}
+        fn on_expire(&self, episode_id: EpisodeId, _episode: &TicTacToe) {
+            self.0.borrow_mut().push(episode_id);
+        }
+    }
+
+    #[derive(Default)]
+    struct RejectHandler(std::cell::RefCell<Vec<(Option<PubKey>, String)>>);
+
+    impl EpisodeEventHandler<TicTacToe> for RejectHandler {
+        fn on_initialize(&self, _episode_id: EpisodeId, _episode: &TicTacToe) {}
+        fn on_command(&self, _: EpisodeId, _: &TicTacToe, _: &TTTCommand, _: Option<PubKey>, _: &PayloadMetadata) {}
+        fn on_rollback(&self, _episode_id: EpisodeId, _episode: &TicTacToe, _metadata: &PayloadMetadata) {}
+        fn on_reject(
+            &self,
+            _: EpisodeId,
+            _: &TicTacToe,
+            _: &TTTCommand,
+            authorization: Option<PubKey>,
+            error: &EpisodeError<TTTError>,
+            _: &PayloadMetadata,
+        ) {
+            self.0.borrow_mut().push((authorization, error.to_string()));
+        }
+    }
+
+    /// Reports every state change, as the handler of a client would
+    struct StateHandler(std::sync::mpsc::Sender<TTTState>);
+
+    impl EpisodeEventHandler<TicTacToe> for StateHandler {
+        fn on_initialize(&self, _episode_id: EpisodeId, episode: &TicTacToe) {
+            let _ = self.0.send(episode.poll());
+        }
+        fn on_command(&self, _: EpisodeId, episode: &TicTacToe, _: &TTTCommand, _: Option<PubKey>, _: &PayloadMetadata) {
+            let _ = self.0.send(episode.poll());
+        }
+        fn on_rollback(&self, _episode_id: EpisodeId, episode: &TicTacToe, _metadata: &PayloadMetadata) {
+            let _ = self.0.send(episode.poll());
+        }
+    }
+
     #[test]
     fn test_ttt_rollback() {
         let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
         let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
-        let mut game = TicTacToe::initialize(vec![p1, p2], &metadata);
-        let rollback = game.execute(&TTTMove { row: 0, col: 0 }, Some(p1), &metadata).unwrap();
+        let mut game = TicTacToe::initialize(vec![p1, p2], &TTTConfig::default(), &metadata);
+        let rollback = game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 0 }), Some(p1), &metadata).unwrap();
         game.rollback(rollback);
-        let _rollback = game.execute(&TTTMove { row: 0, col: 0 }, Some(p1), &metadata).unwrap();
-        let _rollback = game.execute(&TTTMove { row: 1, col: 0 }, Some(p2), &metadata).unwrap();
-        let _rollback = game.execute(&TTTMove { row: 1, col: 1 }, Some(p1), &metadata).unwrap();
-        let _rollback = game.execute(&TTTMove { row: 2, col: 0 }, Some(p2), &metadata).unwrap();
-        let _rollback = game.execute(&TTTMove { row: 0, col: 2 }, Some(p1), &metadata).unwrap();
-        let _rollback = game.execute(&TTTMove { row: 0, col: 1 }, Some(p2), &metadata).unwrap();
+        let _rollback = game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 0 }), Some(p1), &metadata).unwrap();
+        let _rollback = game.execute(&TTTCommand::Move(TTTMove { row: 1, col: 0 }), Some(p2), &metadata).unwrap();
+        let _rollback = game.execute(&TTTCommand::Move(TTTMove { row: 1, col: 1 }), Some(p1), &metadata).unwrap();
+        let _rollback = game.execute(&TTTCommand::Move(TTTMove { row: 2, col: 0 }), Some(p2), &metadata).unwrap();
+        let _rollback = game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 2 }), Some(p1), &metadata).unwrap();
+        let _rollback = game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 1 }), Some(p2), &metadata).unwrap();
 
         // Test a 7th move
         assert_eq!(game.move_history.len(), 6);
         let snapshot = game.clone();
-        let rollback = game.execute(&TTTMove { row: 2, col: 2 }, Some(p1), &metadata).unwrap();
+        let rollback = game.execute(&TTTCommand::Move(TTTMove { row: 2, col: 2 }), Some(p1), &metadata).unwrap();
         assert_eq!(game.move_history.len(), 6);
         assert!(game.rollback(rollback));
         assert_eq!(snapshot, game);
@@ -271,7 +485,8 @@ mod tests {
     async fn test_ttt_engine_rollback() {
         let ((s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
         let episode_id = 11;
-        let new_episode = EpisodeMessage::<TicTacToe>::NewEpisode { episode_id, participants: vec![p1, p2] };
+        let new_episode =
+            EpisodeMessage::<TicTacToe>::NewEpisode { episode_id, participants: vec![p1, p2], params: TTTConfig::default() };
 
         let (sender, receiver) = std::sync::mpsc::channel();
         let mut engine = engine::Engine::<TicTacToe>::new(receiver);
@@ -289,7 +504,7 @@ mod tests {
             })
             .unwrap();
 
-        let cmd = TTTMove { row: 0, col: 0 };
+        let cmd = TTTCommand::Move(TTTMove { row: 0, col: 0 });
         let msg = to_message(&cmd);
         let sig = sign_message(&s1, &msg);
         let step = EpisodeMessage::<TicTacToe>::SignedCommand { episode_id, cmd, pubkey: p1, sig };
@@ -319,4 +534,344 @@ mod tests {
         sender.send(Msg::Exit).unwrap();
         engine_task.await.unwrap();
     }
+
+    /// Plays a full game between two players through transactions built by the generator, relaying their
+    /// payloads to a running engine the way the proxy does, including a reorg reverting the winning move
+    #[tokio::test]
+    async fn test_ttt_scripted_game() {
+        const PREFIX: PrefixType = 858598618;
+        let pattern = generator::pattern_from_prefix(PREFIX);
+        let ((s1, p1), (s2, p2)) = (generate_keypair(), generate_keypair());
+        let signer = secp256k1::Keypair::new(secp256k1::SECP256K1, &mut rand::thread_rng());
+        let addr = Address::new(Prefix::Testnet, Version::PubKey, &signer.x_only_public_key().0.serialize());
+        let generator = generator::TransactionGenerator::new(signer, pattern, PREFIX);
+        let mut utxo = (TransactionOutpoint::new(1u64.into(), 0), UtxoEntry::new(1_000_000, pay_to_address_script(&addr), 0, false));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (state_sender, state_receiver) = std::sync::mpsc::channel();
+        let mut engine = engine::Engine::<TicTacToe, StateHandler>::new(receiver);
+        let engine_task = tokio::task::spawn_blocking(move || {
+            engine.start(vec![StateHandler(state_sender)]);
+        });
+
+        // Accepts each tx in a block of its own, returning the accepting block hash
+        let mut daa = 0;
+        let mut submit = |msg: EpisodeMessage<TicTacToe>| -> Hash {
+            let tx = generator.build_command_transaction(utxo.clone(), &addr, &msg, 5000);
+            utxo = generator::get_first_output_utxo(&tx);
+            assert!(generator::check_pattern(tx.id(), &pattern) && Payload::check_header(&tx.payload, PREFIX));
+            daa += 1;
+            let associated_txs = vec![(tx.id(), Payload::strip_header(tx.payload))];
+            sender
+                .send(Msg::BlkAccepted { accepting_hash: daa.into(), accepting_daa: daa, accepting_time: daa, associated_txs })
+                .unwrap();
+            daa.into()
+        };
+
+        let episode_id = 7;
+        submit(EpisodeMessage::NewEpisode { episode_id, participants: vec![p1, p2], params: TTTConfig::default() });
+        let mut last_block = Hash::default();
+        for (sk, pk, row, col) in [(s1, p1, 0, 0), (s2, p2, 0, 1), (s1, p1, 1, 1), (s2, p2, 0, 2), (s1, p1, 2, 2)] {
+            let step = EpisodeMessage::new_signed_command(episode_id, TTTCommand::Move(TTTMove { row, col }), sk, pk);
+            last_block = submit(step);
+        }
+        sender.send(Msg::BlkReverted { accepting_hash: last_block }).unwrap();
+        submit(EpisodeMessage::new_signed_command(episode_id, TTTCommand::Move(TTTMove { row: 2, col: 2 }), s1, p1));
+        sender.send(Msg::Exit).unwrap();
+        engine_task.await.unwrap();
+
+        // Initialization, five moves, the reverted win and its resubmission
+        let states: Vec<_> = state_receiver.try_iter().collect();
+        assert_eq!(states.len(), 8);
+        for (i, state) in states[..5].iter().enumerate() {
+            assert!(matches!(state.status, TTTGameStatus::InProgress(pk) if pk == [p1, p2][i % 2]));
+        }
+        assert!(matches!(states[5].status, TTTGameStatus::Winner(pk) if pk == p1));
+        assert!(matches!(states[6].status, TTTGameStatus::InProgress(pk) if pk == p1) && states[6].board[2][2].is_none());
+        assert!(matches!(states[7].status, TTTGameStatus::Winner(pk) if pk == p1));
+    }
+
+    #[test]
+    fn test_ttt_snapshot_restore() {
+        let ((s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 7, accepting_time: 0, tx_id: 1u64.into() };
+        let (_sender, receiver) = std::sync::mpsc::channel();
+        let config = engine::EngineConfig { max_episodes: Some(2), ..Default::default() };
+        let mut engine = engine::Engine::<TicTacToe>::with_config(receiver, config);
+
+        let new_episode =
+            EpisodeMessage::<TicTacToe>::NewEpisode { episode_id: 1, participants: vec![p1, p2], params: TTTConfig::default() };
+        engine.handle_message(new_episode, &metadata, &[]);
+        let step = EpisodeMessage::<TicTacToe>::new_signed_command(1, TTTCommand::Move(TTTMove { row: 1, col: 1 }), s1, p1);
+        engine.handle_message(step, &metadata, &[]);
+
+        let snapshot = engine.snapshot(1).unwrap();
+        assert_eq!(snapshot.creation_daa, 7);
+        assert!(engine.snapshot(2).is_none());
+        assert!(matches!(engine.restore(&snapshot, 1), Err(SnapshotError::EpisodeExists(1))));
+
+        engine.restore(&snapshot, 2).unwrap();
+        let restored = engine.snapshot(2).unwrap();
+        assert_eq!(restored.state, snapshot.state);
+        let game: TicTacToe = borsh::from_slice(&restored.state).unwrap();
+        assert_eq!(game.board[1][1], Some(p1));
+        // Restoring is bound by the episode capacity just like new episodes
+        assert!(matches!(engine.restore(&snapshot, 3), Err(SnapshotError::CapacityReached(2))));
+    }
+
+    #[tokio::test]
+    async fn test_ttt_running_engine_snapshot() {
+        let ((s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut engine = engine::Engine::<TicTacToe>::new(receiver);
+        let engine_task = tokio::task::spawn_blocking(move || {
+            engine.start(vec![]);
+        });
+
+        let new_episode =
+            EpisodeMessage::<TicTacToe>::NewEpisode { episode_id: 1, participants: vec![p1, p2], params: TTTConfig::default() };
+        let step = EpisodeMessage::<TicTacToe>::new_signed_command(1, TTTCommand::Move(TTTMove { row: 1, col: 1 }), s1, p1);
+        let associated_txs = vec![(2u64.into(), borsh::to_vec(&new_episode).unwrap()), (3u64.into(), borsh::to_vec(&step).unwrap())];
+        sender.send(Msg::BlkAccepted { accepting_hash: 1u64.into(), accepting_daa: 5, accepting_time: 0, associated_txs }).unwrap();
+
+        // Requests are answered in order with the blocks sent before them
+        let (reply, synced) = tokio::sync::oneshot::channel();
+        sender.send(Msg::Sync { reply }).unwrap();
+        synced.await.unwrap();
+        let (reply, snapshot) = tokio::sync::oneshot::channel();
+        sender.send(Msg::Snapshot { episode_id: 1, reply }).unwrap();
+        let snapshot = snapshot.await.unwrap().unwrap();
+        assert_eq!(snapshot.creation_daa, 5);
+
+        let (reply, restored) = tokio::sync::oneshot::channel();
+        sender.send(Msg::Restore { snapshot: snapshot.clone(), episode_id: 1, reply }).unwrap();
+        assert!(matches!(restored.await.unwrap(), Err(SnapshotError::EpisodeExists(1))));
+        let (reply, restored) = tokio::sync::oneshot::channel();
+        sender.send(Msg::Restore { snapshot: snapshot.clone(), episode_id: 2, reply }).unwrap();
+        restored.await.unwrap().unwrap();
+
+        let (reply, restored) = tokio::sync::oneshot::channel();
+        sender.send(Msg::Snapshot { episode_id: 2, reply }).unwrap();
+        let game: TicTacToe = borsh::from_slice(&restored.await.unwrap().unwrap().state).unwrap();
+        assert_eq!(game.board[1][1], Some(p1));
+
+        sender.send(Msg::Exit).unwrap();
+        engine_task.await.unwrap();
+    }
+
+    #[test]
+    fn test_ttt_expire() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
+        let (_sender, receiver) = std::sync::mpsc::channel();
+        let mut engine = engine::Engine::<TicTacToe, ExpireHandler>::new(receiver);
+        let handlers = [ExpireHandler::default()];
+
+        let new_episode =
+            EpisodeMessage::<TicTacToe>::NewEpisode { episode_id: 3, participants: vec![p1, p2], params: TTTConfig::default() };
+        engine.handle_message(new_episode, &metadata, &handlers);
+        engine.filter_old_episodes(1_000_000, &handlers);
+        assert!(handlers[0].0.borrow().is_empty());
+        engine.filter_old_episodes(10_000_000, &handlers);
+        assert_eq!(*handlers[0].0.borrow(), vec![3]);
+        assert!(engine.snapshot(3).is_none());
+    }
+
+    #[test]
+    fn test_ttt_capacity() {
+        let ((s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata =
+            |daa| PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: daa, accepting_time: 0, tx_id: 1u64.into() };
+        let (_sender, receiver) = std::sync::mpsc::channel();
+        let config = EngineConfig { max_episodes: Some(2), ..Default::default() };
+        let mut engine = engine::Engine::<TicTacToe, ExpireHandler>::with_config(receiver, config);
+        let handlers = [ExpireHandler::default()];
+
+        for (episode_id, daa) in [(1, 0), (2, 1), (3, 2)] {
+            let new_episode =
+                EpisodeMessage::<TicTacToe>::NewEpisode { episode_id, participants: vec![p1, p2], params: TTTConfig::default() };
+            let res = engine.handle_message(new_episode, &metadata(daa), &handlers);
+            assert_eq!(res.is_some(), episode_id != 3);
+        }
+        // Running games are left untouched by the rejected episode
+        assert!(handlers[0].0.borrow().is_empty());
+        assert!(engine.snapshot(1).is_some() && engine.snapshot(2).is_some() && engine.snapshot(3).is_none());
+        let step = EpisodeMessage::<TicTacToe>::new_signed_command(1, TTTCommand::Move(TTTMove { row: 0, col: 0 }), s1, p1);
+        assert!(engine.handle_message(step, &metadata(3), &handlers).is_some());
+
+        let metrics = engine.metrics().snapshot();
+        assert_eq!((metrics.active_episodes, metrics.episodes_created), (2, 2));
+        assert_eq!((metrics.episodes_expired, metrics.episodes_rejected), (0, 1));
+
+        // Capacity frees up once episodes expire
+        engine.filter_old_episodes(10_000_000, &handlers);
+        let new_episode =
+            EpisodeMessage::<TicTacToe>::NewEpisode { episode_id: 3, participants: vec![p1, p2], params: TTTConfig::default() };
+        assert!(engine.handle_message(new_episode, &metadata(10_000_000), &handlers).is_some());
+        assert_eq!(engine.metrics().snapshot().episodes_expired, 2);
+    }
+
+    #[test]
+    fn test_ttt_reject() {
+        let ((s1, p1), (s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
+        let (_sender, receiver) = std::sync::mpsc::channel();
+        let mut engine = engine::Engine::<TicTacToe, RejectHandler>::new(receiver);
+        let handlers = [RejectHandler::default()];
+
+        let new_episode =
+            EpisodeMessage::<TicTacToe>::NewEpisode { episode_id: 1, participants: vec![p1, p2], params: TTTConfig::default() };
+        engine.handle_message(new_episode, &metadata, &handlers);
+        let cmd = TTTCommand::Move(TTTMove { row: 0, col: 0 });
+        let step = EpisodeMessage::<TicTacToe>::new_signed_command(1, cmd, s2, p2);
+        assert_eq!(engine.handle_message(step, &metadata, &handlers), None);
+        // Signed by a different key than the one claimed
+        let forged = EpisodeMessage::<TicTacToe>::new_signed_command(1, cmd, s2, p1);
+        assert_eq!(engine.handle_message(forged, &metadata, &handlers), None);
+        let step = EpisodeMessage::<TicTacToe>::new_signed_command(1, cmd, s1, p1);
+        assert!(engine.handle_message(step, &metadata, &handlers).is_some());
+
+        let rejections = handlers[0].0.borrow();
+        assert_eq!(rejections.len(), 2);
+        assert_eq!(rejections[0].0, Some(p2));
+        assert_eq!(rejections[0].1, EpisodeError::InvalidCommand(TTTError::NotPlayersTurn).to_string());
+        assert_eq!(rejections[1], (Some(p1), EpisodeError::<TTTError>::InvalidSignature.to_string()));
+    }
+
+    #[test]
+    fn test_ttt_check_move() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
+        let mut game = TicTacToe::initialize(vec![p1, p2], &TTTConfig::default(), &metadata);
+        game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 0 }), Some(p1), &metadata).unwrap();
+
+        let state = game.poll();
+        assert!(state.check_move(&TTTMove { row: 1, col: 1 }, p2).is_ok());
+        assert!(matches!(state.check_move(&TTTMove { row: 1, col: 1 }, p1), Err(TTTError::NotPlayersTurn)));
+        assert!(matches!(state.check_move(&TTTMove { row: 0, col: 0 }, p2), Err(TTTError::Occupied)));
+        assert!(matches!(state.check_move(&TTTMove { row: 3, col: 0 }, p2), Err(TTTError::OutOfBounds)));
+
+        let (_s3, p3) = generate_keypair();
+        assert!(matches!(
+            game.execute(&TTTCommand::Move(TTTMove { row: 1, col: 1 }), Some(p3), &metadata),
+            Err(EpisodeError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn test_ttt_claim_timeout() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata =
+            |daa| PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: daa, accepting_time: 0, tx_id: 1u64.into() };
+        let mut game = TicTacToe::initialize(vec![p1, p2], &TTTConfig::default(), &metadata(0));
+        game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 0 }), Some(p1), &metadata(10)).unwrap();
+        assert_eq!(game.poll().turn_deadline, 10 + DEFAULT_TURN_TIMEOUT_DAA);
+
+        let late = metadata(11 + DEFAULT_TURN_TIMEOUT_DAA);
+        let on_time = metadata(10 + DEFAULT_TURN_TIMEOUT_DAA);
+        let res = game.execute(&TTTCommand::ClaimTimeout, Some(p1), &on_time);
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(TTTError::NoTimeout))));
+        let res = game.execute(&TTTCommand::ClaimTimeout, Some(p2), &late);
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(TTTError::NoTimeout))));
+
+        let snapshot = game.clone();
+        let rollback = game.execute(&TTTCommand::ClaimTimeout, Some(p1), &late).unwrap();
+        assert!(matches!(game.poll().status, TTTGameStatus::WonOnTime(pk) if pk == p1));
+        let res = game.execute(&TTTCommand::Move(TTTMove { row: 1, col: 1 }), Some(p2), &late);
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(TTTError::GameOver))));
+
+        assert!(game.rollback(rollback));
+        assert_eq!(snapshot, game);
+    }
+
+    #[test]
+    fn test_ttt_resign() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
+        let mut game = TicTacToe::initialize(vec![p1, p2], &TTTConfig::default(), &metadata);
+        game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 0 }), Some(p1), &metadata).unwrap();
+
+        // Resigning does not require the turn
+        let snapshot = game.clone();
+        let rollback = game.execute(&TTTCommand::Resign, Some(p1), &metadata).unwrap();
+        assert!(matches!(game.poll().status, TTTGameStatus::Resigned(pk) if pk == p1));
+        let res = game.execute(&TTTCommand::Resign, Some(p2), &metadata);
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(TTTError::GameOver))));
+
+        assert!(game.rollback(rollback));
+        assert_eq!(snapshot, game);
+    }
+
+    #[test]
+    fn test_ttt_generalized_board() {
+        let players: Vec<_> = (0..3).map(|_| generate_keypair().1).collect();
+        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
+        let config = TTTConfig { size: 5, win_length: 4, max_symbols: None, ..Default::default() };
+        let mut game = TicTacToe::initialize(players.clone(), &config, &metadata);
+        let state = game.poll();
+        assert_eq!((state.board.len(), state.symbol(players[2])), (5, 'A'));
+        assert!(matches!(state.check_move(&TTTMove { row: 4, col: 5 }, players[0]), Err(TTTError::OutOfBounds)));
+
+        // The first player builds an anti-diagonal while the others play along the top row
+        let moves = [(0, 4), (0, 0), (0, 1), (1, 3), (0, 2), (0, 3), (2, 2), (1, 0), (1, 1), (3, 1)];
+        let mut rollbacks = Vec::new();
+        for (i, (row, col)) in moves.into_iter().enumerate() {
+            assert!(matches!(game.poll().status, TTTGameStatus::InProgress(pk) if pk == players[i % 3]));
+            let cmd = TTTCommand::Move(TTTMove { row, col });
+            rollbacks.push(game.execute(&cmd, Some(players[i % 3]), &metadata).unwrap());
+        }
+        assert!(matches!(game.poll().status, TTTGameStatus::Winner(pk) if pk == players[0]));
+        let res = game.execute(&TTTCommand::Move(TTTMove { row: 4, col: 4 }), Some(players[1]), &metadata);
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(TTTError::GameOver))));
+
+        assert!(game.rollback(rollbacks.pop().unwrap()));
+        assert!(matches!(game.poll().status, TTTGameStatus::InProgress(pk) if pk == players[0]));
+
+        // Ending the game on behalf of everyone is limited to two players
+        let late = PayloadMetadata { accepting_daa: DEFAULT_TURN_TIMEOUT_DAA + 1, ..metadata.clone() };
+        for cmd in [TTTCommand::ClaimTimeout, TTTCommand::Resign] {
+            let res = game.execute(&cmd, Some(players[1]), &late);
+            assert!(matches!(res, Err(EpisodeError::InvalidCommand(TTTError::NotTwoPlayers))));
+        }
+
+        // Too few, too many or repeated participants make the episode invalid
+        let many: Vec<_> = (0..=MAX_PLAYERS).map(|_| generate_keypair().1).collect();
+        for invalid in [vec![], vec![players[0]], vec![players[0], players[1], players[0]], many] {
+            let mut game = TicTacToe::initialize(invalid, &TTTConfig::default(), &metadata);
+            assert!(matches!(game.poll().status, TTTGameStatus::Invalid));
+            let res = game.execute(&TTTCommand::Move(TTTMove { row: 0, col: 0 }), Some(players[0]), &metadata);
+            assert!(matches!(res, Err(EpisodeError::Unauthorized)));
+        }
+
+        // Oversized params are clamped rather than trusted
+        let config = TTTConfig { size: 1000, win_length: 0, max_symbols: Some(0), turn_timeout: u64::MAX, ..Default::default() };
+        let game = TicTacToe::initialize(players, &config, &metadata);
+        let expected = TTTConfig {
+            size: MAX_BOARD_SIZE,
+            win_length: 1,
+            max_symbols: None,
+            turn_timeout: MAX_TURN_TIMEOUT_DAA,
+            ..Default::default()
+        };
+        assert_eq!(game.poll().config, expected);
+        assert_eq!(game.poll().turn_deadline, MAX_TURN_TIMEOUT_DAA);
+    }
+
+    #[test]
+    fn test_ttt_rule_variants() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let metadata = PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: 0, accepting_time: 0, tx_id: 1u64.into() };
+        let config = TTTConfig { misere: true, gravity: true, ..Default::default() };
+        let mut game = TicTacToe::initialize(vec![p1, p2], &config, &metadata);
+        // Removing the oldest symbol would leave the column above it floating, so gravity keeps every symbol
+        assert_eq!(game.poll().config.max_symbols, None);
+        assert!(matches!(game.poll().check_move(&TTTMove { row: 1, col: 0 }, p1), Err(TTTError::NotLowestCell)));
+
+        // Stacking the first column completes a line for X, which loses under misère rules
+        for (i, (row, col)) in [(2, 0), (2, 1), (1, 0), (1, 1), (0, 0)].into_iter().enumerate() {
+            let player = [p1, p2][i % 2];
+            game.execute(&TTTCommand::Move(TTTMove { row, col }), Some(player), &metadata).unwrap();
+        }
+        assert!(matches!(game.poll().status, TTTGameStatus::Loser(pk) if pk == p1));
+    }
 }
@@ -1,10 +1,6 @@
 use clap::Parser;
 use itertools::Itertools;
-use kaspa_addresses::{Address, Prefix, Version};
-use kaspa_consensus_core::{
-    network::{NetworkId, NetworkType},
-    tx::{TransactionOutpoint, UtxoEntry},
-};
+use kaspa_addresses::{Address, Version};
 use kaspa_wrpc_client::prelude::*;
 use log::*;
 use rand::Rng;
@@ -16,18 +12,20 @@ use std::{
         mpsc::channel,
         Arc,
     },
+    time::Duration,
 };
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 use kdapp::{
-    engine::{self, EpisodeMessage},
-    episode::{EpisodeEventHandler, EpisodeId},
+    engine::{self, EngineConfig, EpisodeMessage},
+    episode::{EpisodeError, EpisodeEventHandler, EpisodeId, PayloadMetadata},
     generator::{self, PatternType, PrefixType},
     pki::{generate_keypair, PubKey},
-    proxy::{self, connect_client},
+    proxy::{self, connect_client, connect_client_with_failover, CommandSubmitter},
+    utils::{self, network::NetworkProfile},
 };
 
-use game::{TTTMove, TTTState, TicTacToe};
+use game::{TTTCommand, TTTConfig, TTTGameStatus, TTTMove, TTTState, TicTacToe, DEFAULT_TURN_TIMEOUT_DAA};
 
 pub mod game;
 
@@ -42,17 +40,47 @@ struct Args {
     #[arg(short = 'g', long)]
     game_private_key: Option<String>,
 
-    /// Game opponent public key
+    /// Game opponent public key. May be repeated for games with more than two players
     #[arg(short = 'o', long)]
-    game_opponent_key: Option<String>,
+    game_opponent_key: Vec<String>,
+
+    /// Side length of the board, when initiating the game
+    #[arg(long, default_value_t = 3)]
+    size: usize,
+
+    /// Number of symbols in a row needed to win, when initiating the game
+    #[arg(long, default_value_t = 3)]
+    win_length: usize,
+
+    /// Maximum number of symbols on board, beyond which each move removes the oldest one. Defaults to 6 on a
+    /// 3x3 board and to no limit otherwise. Ignored by the gravity variant
+    #[arg(long)]
+    max_symbols: Option<usize>,
+
+    /// Plays the misère variant, where completing a line loses, when initiating the game
+    #[arg(long, default_value_t = false)]
+    misere: bool,
+
+    /// Plays the gravity variant, where symbols drop to the lowest free cell of a column, when initiating the game
+    #[arg(long, default_value_t = false)]
+    gravity: bool,
+
+    /// Number of DAA scores each player has to move before the opponent may claim the win, when initiating the game
+    #[arg(long, default_value_t = DEFAULT_TURN_TIMEOUT_DAA)]
+    turn_timeout: u64,
 
     /// Indicates whether to run the interaction over mainnet (default: testnet 10)
     #[arg(short, long, default_value_t = false)]
     mainnet: bool,
 
+    /// Fee in sompi attached to each game transaction. Defaults to the default fee of the network
+    #[arg(long)]
+    fee: Option<u64>,
+
     /// Specifies the wRPC Kaspa Node URL to use. Usage: <wss://localhost>. Defaults to the Public Node Network (PNN).
+    /// May be repeated, in which case the first responsive node is used
     #[arg(short, long)]
-    wrpc_url: Option<String>,
+    wrpc_url: Vec<String>,
 
     /// Logging level for all subsystems {off, error, warn, info, debug, trace}
     ///  -- You may also specify `<subsystem>=<level>,<subsystem2>=<level>,...` to set the log level for individual subsystems
@@ -69,11 +97,8 @@ async fn main() {
     kaspa_core::log::init_logger(None, &args.log_level);
 
     // Select network
-    let (network, prefix) = if args.mainnet {
-        (NetworkId::new(NetworkType::Mainnet), Prefix::Mainnet)
-    } else {
-        (NetworkId::with_suffix(NetworkType::Testnet, 10), Prefix::Testnet)
-    };
+    let profile = if args.mainnet { NetworkProfile::MAINNET } else { NetworkProfile::TESTNET_10 };
+    let (network, prefix) = (profile.network_id, profile.prefix());
 
     // Generate or obtain Kaspa private key
     let kaspa_signer = if let Some(private_key_hex) = args.kaspa_private_key {
@@ -88,6 +113,9 @@ async fn main() {
             String::from(&Address::new(prefix, Version::PubKey, &pk.x_only_public_key().0.serialize())),
             sk.display_secret()
         );
+        if let Some(faucet_url) = profile.faucet_url {
+            info!("Testnet funds are available from the faucet at {}", faucet_url);
+        }
         return;
     };
 
@@ -106,12 +134,22 @@ async fn main() {
 
     info!("Player public key: {}", player_pk);
 
-    // ... and opponent pk
-    let opponent_pk = args.game_opponent_key.map(|opponent_key_hex| PubKey(PublicKey::from_str(&opponent_key_hex).unwrap()));
+    // ... and opponent pks
+    let opponents: Vec<PubKey> =
+        args.game_opponent_key.iter().map(|opponent_key_hex| PubKey(PublicKey::from_str(opponent_key_hex).unwrap())).collect();
+    let max_symbols = args.max_symbols.or((args.size == 3 && !args.gravity).then_some(6));
+    let game_config = TTTConfig {
+        size: args.size,
+        win_length: args.win_length,
+        max_symbols,
+        misere: args.misere,
+        gravity: args.gravity,
+        turn_timeout: args.turn_timeout,
+    };
 
     // Connect kaspad clients
-    let kaspad = connect_client(network, args.wrpc_url.clone()).await.unwrap();
-    let player_kaspad = connect_client(network, args.wrpc_url).await.unwrap();
+    let kaspad = connect_client_with_failover(network, &args.wrpc_url).await.unwrap();
+    let player_kaspad = connect_client(network, kaspad.url()).await.unwrap(); // Pin to the same node
 
     // Define channels and exit flag
     let (sender, receiver) = channel();
@@ -119,38 +157,56 @@ async fn main() {
     let exit_signal = Arc::new(AtomicBool::new(false));
     let exit_signal_receiver = exit_signal.clone();
 
+    // On Ctrl-C or SIGTERM, stop the listener which in turn signals the engine to exit
+    let interrupted = proxy::exit_on_ctrl_c(exit_signal.clone());
+
+    // Keep episodes for as long as their txs are retained by the node
+    let episode_lifetime = proxy::pruning_window(&kaspad).await.unwrap();
+    info!("Episode lifetime: {} DAA score units", episode_lifetime);
+
     // Run the engine
-    let mut engine = engine::Engine::<TicTacToe, TTTHandler>::new(receiver);
+    let config = EngineConfig { episode_lifetime, ..Default::default() };
+    let mut engine = engine::Engine::<TicTacToe, TTTHandler>::with_config(receiver, config);
     let engine_task = tokio::task::spawn_blocking(move || {
         engine.start(vec![TTTHandler { sender: response_sender, player: player_pk }]);
     });
 
     // Run the player task
+    let fee = args.fee.unwrap_or(profile.default_fee);
+    let generator = generator::TransactionGenerator::new(kaspa_signer, PATTERN, PREFIX);
+    let submitter = CommandSubmitter::new(&player_kaspad, generator, kaspa_addr, profile, fee, !opponents.is_empty()).await.unwrap();
     let player_task = tokio::spawn(async move {
-        play_ttt(player_kaspad, kaspa_signer, kaspa_addr, response_receiver, exit_signal, sk, player_pk, opponent_pk).await;
+        play_ttt(player_kaspad, submitter, response_receiver, exit_signal, sk, player_pk, opponents, game_config).await;
     });
 
     // Run the kaspad listener
-    proxy::run_listener(kaspad, std::iter::once((PREFIX, (PATTERN, sender))).collect(), exit_signal_receiver).await;
+    let engines = std::iter::once((PREFIX, (PATTERN, sender))).collect();
+    proxy::run_listener(kaspad, network, args.wrpc_url, engines, exit_signal_receiver).await;
 
     engine_task.await.unwrap();
+    if interrupted.load(Ordering::Relaxed) {
+        // The player task might be blocked on stdin, so exit without awaiting it
+        std::process::exit(0);
+    }
     player_task.await.unwrap();
 }
 
-// TODO: derive pattern from prefix (using prefix as a random seed for composing the pattern)
-const PATTERN: PatternType = [(7, 0), (32, 1), (45, 0), (99, 1), (113, 0), (126, 1), (189, 0), (200, 1), (211, 0), (250, 1)];
 const PREFIX: PrefixType = 858598618;
-const FEE: u64 = 5000;
+const PATTERN: PatternType = generator::pattern_from_prefix(PREFIX);
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A game state together with the metadata of the accepted transaction which produced it, if any
+type StateUpdate = (EpisodeId, TTTState, Option<PayloadMetadata>);
 
 struct TTTHandler {
-    sender: UnboundedSender<(EpisodeId, TTTState)>,
+    sender: UnboundedSender<StateUpdate>,
     player: PubKey, // The local player pubkey
 }
 
 impl EpisodeEventHandler<TicTacToe> for TTTHandler {
     fn on_initialize(&self, episode_id: kdapp::episode::EpisodeId, episode: &TicTacToe) {
         if episode.players.contains(&self.player) {
-            let _ = self.sender.send((episode_id, episode.poll()));
+            let _ = self.sender.send((episode_id, episode.poll(), None));
         }
     }
 
@@ -160,88 +216,156 @@ impl EpisodeEventHandler<TicTacToe> for TTTHandler {
         episode: &TicTacToe,
         _cmd: &<TicTacToe as kdapp::episode::Episode>::Command,
         _authorization: Option<PubKey>,
-        _metadata: &kdapp::episode::PayloadMetadata,
+        metadata: &kdapp::episode::PayloadMetadata,
     ) {
         if episode.players.contains(&self.player) {
-            let _ = self.sender.send((episode_id, episode.poll()));
+            let _ = self.sender.send((episode_id, episode.poll(), Some(metadata.clone())));
+        }
+    }
+
+    fn on_rollback(&self, episode_id: kdapp::episode::EpisodeId, episode: &TicTacToe, metadata: &kdapp::episode::PayloadMetadata) {
+        if episode.players.contains(&self.player) {
+            warn!("A DAG reorg reverted tx {} in episode {}", metadata.tx_id, episode_id);
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+
+    fn on_reject(
+        &self,
+        episode_id: kdapp::episode::EpisodeId,
+        episode: &TicTacToe,
+        cmd: &<TicTacToe as kdapp::episode::Episode>::Command,
+        authorization: Option<PubKey>,
+        error: &EpisodeError<<TicTacToe as kdapp::episode::Episode>::CommandError>,
+        _metadata: &kdapp::episode::PayloadMetadata,
+    ) {
+        if authorization == Some(self.player) && error.is_authenticated() {
+            println!("Your command {:?} was rejected: {}", cmd, error);
+            // Wake the player task, which would otherwise keep waiting for the command to take effect
+            let _ = self.sender.send((episode_id, episode.poll(), None));
         }
     }
+}
 
-    fn on_rollback(&self, _episode_id: kdapp::episode::EpisodeId, _episode: &TicTacToe) {}
+/// Prints a game state along with when and where the transaction producing it was accepted
+fn print_update(state: &TTTState, metadata: &Option<PayloadMetadata>) {
+    state.print();
+    if let Some(metadata) = metadata {
+        println!("{}", utils::format_acceptance(metadata));
+    }
 }
 
 async fn play_ttt(
     kaspad: KaspaRpcClient,
-    kaspa_signer: Keypair,
-    kaspa_addr: Address,
-    mut response_receiver: UnboundedReceiver<(EpisodeId, TTTState)>,
+    mut submitter: CommandSubmitter,
+    mut response_receiver: UnboundedReceiver<StateUpdate>,
     exit_signal: Arc<AtomicBool>,
     sk: SecretKey,
     player_pk: PubKey,
-    opponent_pk: Option<PubKey>,
+    opponents: Vec<PubKey>,
+    config: TTTConfig,
 ) {
-    let entries = kaspad.get_utxos_by_addresses(vec![kaspa_addr.clone()]).await.unwrap();
-    assert!(!entries.is_empty());
-    // Try to avoid collisions if both players are using the same kaspa address
-    let entry = if opponent_pk.is_some() { entries.first().cloned() } else { entries.last().cloned() };
-    let mut utxo = entry.map(|entry| (TransactionOutpoint::from(entry.outpoint), UtxoEntry::from(entry.utxo_entry))).unwrap();
-
-    let generator = generator::TransactionGenerator::new(kaspa_signer, PATTERN, PREFIX);
-
-    // When opponent pk is passed, we are expected to initiate the game
-    if let Some(opponent_pk) = opponent_pk {
+    // When opponent pks are passed, we are expected to initiate the game
+    if !opponents.is_empty() {
         // Use a simple rand method
         // TODO: a complete implementation must handle collisions
         let episode_id = rand::thread_rng().gen();
-        let new_episode = EpisodeMessage::<TicTacToe>::NewEpisode { episode_id, participants: vec![player_pk, opponent_pk] };
-        let tx = generator.build_command_transaction(utxo, &kaspa_addr, &new_episode, FEE);
-        info!("Submitting initialize command: {}", tx.id());
-        let _res = kaspad.submit_transaction(tx.as_ref().into(), false).await.unwrap();
-        utxo = generator::get_first_output_utxo(&tx);
+        let new_episode = EpisodeMessage::<TicTacToe>::NewEpisode {
+            episode_id,
+            participants: std::iter::once(player_pk).chain(opponents).collect(),
+            params: config,
+        };
+        submitter.submit(&kaspad, &new_episode).await.unwrap();
     }
 
-    let (episode_id, mut state) = response_receiver.recv().await.unwrap();
-    state.print();
+    // A closed channel means the engine has exited
+    let Some((episode_id, mut state, mut metadata)) = response_receiver.recv().await else {
+        return;
+    };
+    print_update(&state, &metadata);
 
     let mut received_id = episode_id;
     let mut input = String::new();
 
+    // Timeouts and resigning end the game for everyone, so the episode only allows them between two players
+    let two_players = state.players.len() == 2;
+
     loop {
-        while let game::TTTGameStatus::InProgress(pk) = state.status {
+        let mut claimed = false;
+        while let TTTGameStatus::InProgress(pk) = state.status {
             if received_id == episode_id && player_pk == pk {
                 break;
             }
-            // Loop until our turn
-            (received_id, state) = response_receiver.recv().await.unwrap();
+            // Loop until our turn, claiming the win if the opponent lets their deadline pass
+            let update = tokio::select! {
+                update = response_receiver.recv() => update,
+                _ = tokio::time::sleep(DEADLINE_POLL_INTERVAL) => {
+                    if two_players
+                        && !claimed
+                        && received_id == episode_id
+                        && proxy::virtual_daa_score(&kaspad).await.is_some_and(|daa| daa > state.turn_deadline)
+                    {
+                        println!("Opponent ran out of time, claiming the win");
+                        let step = EpisodeMessage::<TicTacToe>::new_signed_command(episode_id, TTTCommand::ClaimTimeout, sk, player_pk);
+                        submitter.submit(&kaspad, &step).await.unwrap();
+                        claimed = true;
+                    }
+                    continue;
+                }
+            };
+            let Some(update) = update else {
+                return;
+            };
+            (received_id, state, metadata) = update;
             if received_id == episode_id {
-                state.print();
+                claimed = false;
+                print_update(&state, &metadata);
             }
         }
 
-        if !matches!(state.status, game::TTTGameStatus::InProgress(..)) {
+        if !matches!(state.status, TTTGameStatus::InProgress(..)) {
             exit_signal.store(true, Ordering::Relaxed);
             break;
         }
 
-        input.clear();
-        println!("Insert move: [row, col]");
-        std::io::stdin().read_line(&mut input).unwrap();
-        let (row, col) = input.trim().split(',').map(|p| p.trim().parse::<usize>().unwrap()).collect_tuple().unwrap();
-
-        let cmd = TTTMove { row, col };
+        let cmd = loop {
+            input.clear();
+            if two_players {
+                println!("Insert move before DAA score {}: [row, col], or `resign`", state.turn_deadline);
+            } else {
+                println!("Insert move: [row, col]");
+            }
+            std::io::stdin().read_line(&mut input).unwrap();
+            if two_players && input.trim() == "resign" {
+                break TTTCommand::Resign;
+            }
+            let Some((Some(row), Some(col))) = input.trim().split(',').map(|p| p.trim().parse::<usize>().ok()).collect_tuple() else {
+                println!("Invalid input, expected: row, col");
+                continue;
+            };
+            let mv = TTTMove { row, col };
+            // Validate locally before spending a fee on a move the engine would reject
+            match state.check_move(&mv, player_pk) {
+                Ok(()) => break TTTCommand::Move(mv),
+                Err(err) => println!("Invalid move: {}", err),
+            }
+        };
         let step = EpisodeMessage::<TicTacToe>::new_signed_command(episode_id, cmd, sk, player_pk);
 
-        let tx = generator.build_command_transaction(utxo, &kaspa_addr, &step, FEE);
-        info!("Submitting: {}", tx.id());
-        let _res = kaspad.submit_transaction(tx.as_ref().into(), false).await.unwrap();
-        utxo = generator::get_first_output_utxo(&tx);
-
-        (received_id, state) = response_receiver.recv().await.unwrap();
-
-        // Wait for current move
-        while received_id != episode_id || state.board[cmd.row][cmd.col].is_none() {
-            (received_id, state) = response_receiver.recv().await.unwrap();
+        submitter.submit(&kaspad, &step).await.unwrap();
+
+        // Wait for current command, or for the opponent claiming a win on time if it landed too late. Updates
+        // without metadata stem from a rejection or a reorg, after which the turn is re-evaluated
+        loop {
+            let Some(update) = response_receiver.recv().await else {
+                return;
+            };
+            (received_id, state, metadata) = update;
+            let played = matches!(cmd, TTTCommand::Move(mv) if state.board[mv.row][mv.col].is_some());
+            if received_id == episode_id && (played || metadata.is_none() || !matches!(state.status, TTTGameStatus::InProgress(..))) {
+                break;
+            }
         }
-        state.print();
+        print_update(&state, &metadata);
     }
 }
@@ -0,0 +1,280 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use kdapp::{
+    episode::{Episode, EpisodeError, PayloadMetadata},
+    pki::PubKey,
+};
+use log::info;
+use sha2::{Digest, Sha256};
+
+/// Number of DAA scores (roughly 10 minutes at 10 BPS) after initialization during which ballots are accepted
+pub const VOTING_PERIOD_DAA: u64 = 6000;
+
+/// Number of DAA scores after the voting period during which ballots may be revealed
+pub const REVEAL_PERIOD_DAA: u64 = 6000;
+
+#[derive(Debug, BorshDeserialize, BorshSerialize)]
+pub enum VotingError {
+    AlreadyVoted,
+    AlreadyRevealed,
+    NoBallot,
+    BallotMismatch,
+    VotingClosed,
+    RevealNotOpen,
+    RevealClosed,
+}
+
+impl std::fmt::Display for VotingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VotingError::AlreadyVoted => write!(f, "Voter already cast a ballot."),
+            VotingError::AlreadyRevealed => write!(f, "Voter already revealed their ballot."),
+            VotingError::NoBallot => write!(f, "Voter did not cast a ballot."),
+            VotingError::BallotMismatch => write!(f, "Revealed vote does not match the ballot."),
+            VotingError::VotingClosed => write!(f, "The voting period is over."),
+            VotingError::RevealNotOpen => write!(f, "Ballots can only be revealed after the voting period."),
+            VotingError::RevealClosed => write!(f, "The reveal period is over."),
+        }
+    }
+}
+
+impl std::error::Error for VotingError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Vote {
+    Yes,
+    No,
+    Abstain,
+}
+
+pub type Salt = [u8; 32];
+pub type Ballot = [u8; 32];
+
+/// Hashes a vote with a secret salt. The voter key is included so that a ballot cannot be copied
+/// and later revealed by someone else.
+pub fn ballot(voter: PubKey, vote: Vote, salt: &Salt) -> Ballot {
+    let mut hasher = Sha256::new();
+    hasher.update(voter.0.serialize());
+    hasher.update([vote as u8]);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
+pub enum VotingCommand {
+    Cast(Ballot),
+    Reveal { vote: Vote, salt: Salt },
+}
+
+#[derive(Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
+pub enum VotingRollback {
+    Cast { voter: usize },
+    Reveal { voter: usize },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Tally {
+    pub yes: usize,
+    pub no: usize,
+    pub abstain: usize,
+}
+
+#[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
+pub struct VotingState {
+    pub voters: usize,
+    pub ballots: usize,
+    pub revealed: usize,
+    /// Counts revealed votes only, so it is final once every ballot is revealed or the reveal period ends
+    pub tally: Tally,
+    pub voting_ends: u64,
+    pub reveal_ends: u64,
+}
+
+impl VotingState {
+    /// Whether no further reveal can change the tally, based on the latest known DAA score
+    pub fn is_final(&self, daa_score: u64) -> bool {
+        (self.ballots == self.revealed && daa_score > self.voting_ends) || daa_score > self.reveal_ends
+    }
+
+    pub fn print(&self) {
+        println!("ballots: {} of {} voters, revealed: {}", self.ballots, self.voters, self.revealed);
+        println!("yes: {}, no: {}, abstain: {}", self.tally.yes, self.tally.no, self.tally.abstain);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Voting {
+    pub(crate) voters: Vec<PubKey>,
+    ballots: Vec<Option<Ballot>>,
+    votes: Vec<Option<Vote>>,
+    voting_ends: u64,
+}
+
+impl Episode for Voting {
+    type Command = VotingCommand;
+    type CommandRollback = VotingRollback;
+    type CommandError = VotingError;
+    type InitParams = ();
+
+    fn initialize(participants: Vec<PubKey>, _params: &(), metadata: &PayloadMetadata) -> Self {
+        info!("[Voting] initialize: {:?}", participants);
+        let voters = participants.len();
+        Self {
+            voters: participants,
+            ballots: vec![None; voters],
+            votes: vec![None; voters],
+            voting_ends: metadata.accepting_daa + VOTING_PERIOD_DAA,
+        }
+    }
+
+    fn execute(
+        &mut self,
+        cmd: &Self::Command,
+        authorization: Option<PubKey>,
+        metadata: &PayloadMetadata,
+    ) -> Result<Self::CommandRollback, EpisodeError<Self::CommandError>> {
+        let Some(voter_pk) = authorization else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        let Some(voter) = self.voters.iter().position(|&pk| pk == voter_pk).filter(|_| self.has_valid_voters()) else {
+            return Err(EpisodeError::Unauthorized);
+        };
+        let daa = metadata.accepting_daa;
+
+        let rollback = match cmd {
+            VotingCommand::Cast(ballot) => {
+                if daa > self.voting_ends {
+                    return Err(EpisodeError::InvalidCommand(VotingError::VotingClosed));
+                }
+                if self.ballots[voter].is_some() {
+                    return Err(EpisodeError::InvalidCommand(VotingError::AlreadyVoted));
+                }
+                self.ballots[voter] = Some(*ballot);
+                VotingRollback::Cast { voter }
+            }
+            VotingCommand::Reveal { vote, salt } => {
+                // Revealing during the voting period would let later voters react to the running tally
+                if daa <= self.voting_ends {
+                    return Err(EpisodeError::InvalidCommand(VotingError::RevealNotOpen));
+                }
+                if daa > self.reveal_ends() {
+                    return Err(EpisodeError::InvalidCommand(VotingError::RevealClosed));
+                }
+                let Some(cast) = self.ballots[voter] else {
+                    return Err(EpisodeError::InvalidCommand(VotingError::NoBallot));
+                };
+                if self.votes[voter].is_some() {
+                    return Err(EpisodeError::InvalidCommand(VotingError::AlreadyRevealed));
+                }
+                if cast != ballot(voter_pk, *vote, salt) {
+                    return Err(EpisodeError::InvalidCommand(VotingError::BallotMismatch));
+                }
+                self.votes[voter] = Some(*vote);
+                VotingRollback::Reveal { voter }
+            }
+        };
+
+        info!("[Voting] execute: {:?}, {:?}", voter_pk, cmd);
+        Ok(rollback)
+    }
+
+    fn rollback(&mut self, rollback: VotingRollback) -> bool {
+        match rollback {
+            VotingRollback::Cast { voter } => self.ballots[voter].take().is_some(),
+            VotingRollback::Reveal { voter } => self.votes[voter].take().is_some(),
+        }
+    }
+}
+
+impl Voting {
+    /// Whether `pk` takes part in a valid vote
+    pub fn is_voter(&self, pk: PubKey) -> bool {
+        self.has_valid_voters() && self.voters.contains(&pk)
+    }
+
+    /// Participants come straight from the episode tx, so they are checked to be distinct before use. A repeated key
+    /// would hold a ballot that can never be cast
+    fn has_valid_voters(&self) -> bool {
+        let voters = &self.voters;
+        !voters.is_empty() && voters.iter().enumerate().all(|(i, pk)| !voters[..i].contains(pk))
+    }
+
+    pub fn poll(&self) -> VotingState {
+        let mut tally = Tally::default();
+        for vote in self.votes.iter().flatten() {
+            match vote {
+                Vote::Yes => tally.yes += 1,
+                Vote::No => tally.no += 1,
+                Vote::Abstain => tally.abstain += 1,
+            }
+        }
+        VotingState {
+            voters: self.voters.len(),
+            ballots: self.ballots.iter().flatten().count(),
+            revealed: self.votes.iter().flatten().count(),
+            tally,
+            voting_ends: self.voting_ends,
+            reveal_ends: self.reveal_ends(),
+        }
+    }
+
+    fn reveal_ends(&self) -> u64 {
+        self.voting_ends + REVEAL_PERIOD_DAA
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kdapp::pki::generate_keypair;
+
+    fn metadata(daa: u64) -> PayloadMetadata {
+        PayloadMetadata { accepting_hash: 0u64.into(), accepting_daa: daa, accepting_time: 0, tx_id: 1u64.into() }
+    }
+
+    #[test]
+    fn test_voting_commit_reveal() {
+        let voters: Vec<_> = (0..3).map(|_| generate_keypair().1).collect();
+        let mut voting = Voting::initialize(voters.clone(), &(), &metadata(0));
+        let votes = [Vote::Yes, Vote::No, Vote::Yes];
+        for (i, (&pk, vote)) in voters.iter().zip(votes).enumerate() {
+            voting.execute(&VotingCommand::Cast(ballot(pk, vote, &[i as u8; 32])), Some(pk), &metadata(1)).unwrap();
+        }
+
+        // Ballots are hidden until revealed, and cannot be revealed while voting is open
+        let reveal = |i: usize| VotingCommand::Reveal { vote: votes[i], salt: [i as u8; 32] };
+        assert_eq!(voting.poll().tally, Tally::default());
+        let res = voting.execute(&reveal(0), Some(voters[0]), &metadata(VOTING_PERIOD_DAA));
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(VotingError::RevealNotOpen))));
+        let res = voting.execute(&VotingCommand::Cast([0; 32]), Some(voters[0]), &metadata(VOTING_PERIOD_DAA + 1));
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(VotingError::VotingClosed))));
+
+        let open = metadata(VOTING_PERIOD_DAA + 1);
+        let res = voting.execute(&reveal(0), Some(voters[1]), &open);
+        assert!(matches!(res, Err(EpisodeError::InvalidCommand(VotingError::BallotMismatch))));
+        voting.execute(&reveal(0), Some(voters[0]), &open).unwrap();
+        let rollback = voting.execute(&reveal(1), Some(voters[1]), &open).unwrap();
+        assert!(!voting.poll().is_final(VOTING_PERIOD_DAA + 1));
+        assert!(voting.poll().is_final(VOTING_PERIOD_DAA + REVEAL_PERIOD_DAA + 1));
+
+        assert!(voting.rollback(rollback));
+        assert_eq!(voting.poll().tally, Tally { yes: 1, no: 0, abstain: 0 });
+        voting.execute(&reveal(1), Some(voters[1]), &open).unwrap();
+        voting.execute(&reveal(2), Some(voters[2]), &open).unwrap();
+        let state = voting.poll();
+        assert_eq!(state.tally, Tally { yes: 2, no: 1, abstain: 0 });
+        assert!(state.is_final(VOTING_PERIOD_DAA + 1));
+
+        let (_sk, outsider) = generate_keypair();
+        assert!(matches!(voting.execute(&reveal(0), Some(outsider), &open), Err(EpisodeError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_voting_repeated_voters() {
+        let ((_s1, p1), (_s2, p2)) = (generate_keypair(), generate_keypair());
+        let mut voting = Voting::initialize(vec![p1, p2, p1], &(), &metadata(0));
+        assert!(!voting.is_voter(p1) && !voting.is_voter(p2));
+        let res = voting.execute(&VotingCommand::Cast(ballot(p2, Vote::Yes, &[0; 32])), Some(p2), &metadata(1));
+        assert!(matches!(res, Err(EpisodeError::Unauthorized)));
+        assert!(!Voting::initialize(vec![], &(), &metadata(0)).is_voter(p1));
+    }
+}
@@ -0,0 +1,311 @@
+use clap::Parser;
+use kaspa_addresses::{Address, Version};
+use kaspa_wrpc_client::prelude::*;
+use log::*;
+use rand::Rng;
+use secp256k1::{Keypair, PublicKey, SecretKey};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use kdapp::{
+    engine::{self, EngineConfig, EpisodeMessage},
+    episode::{EpisodeError, EpisodeEventHandler, EpisodeId, PayloadMetadata},
+    generator::{self, PatternType, PrefixType},
+    pki::{generate_keypair, PubKey},
+    proxy::{self, connect_client, connect_client_with_failover, CommandSubmitter},
+    utils::{self, network::NetworkProfile},
+};
+
+use game::{Vote, Voting, VotingCommand, VotingState};
+
+pub mod game;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Kaspa schnorr private key
+    #[arg(short, long)]
+    kaspa_private_key: Option<String>,
+
+    /// Game private key
+    #[arg(short = 'g', long)]
+    game_private_key: Option<String>,
+
+    /// Public keys of the other voters. When passed, this instance opens the vote. May be repeated
+    #[arg(short, long)]
+    voter: Vec<String>,
+
+    /// Indicates whether to run the interaction over mainnet (default: testnet 10)
+    #[arg(short, long, default_value_t = false)]
+    mainnet: bool,
+
+    /// Fee in sompi attached to each game transaction. Defaults to the default fee of the network
+    #[arg(long)]
+    fee: Option<u64>,
+
+    /// Specifies the wRPC Kaspa Node URL to use. Usage: <wss://localhost>. Defaults to the Public Node Network (PNN).
+    /// May be repeated, in which case the first responsive node is used
+    #[arg(short, long)]
+    wrpc_url: Vec<String>,
+
+    /// Logging level for all subsystems {off, error, warn, info, debug, trace}
+    ///  -- You may also specify `<subsystem>=<level>,<subsystem2>=<level>,...` to set the log level for individual subsystems
+    #[arg(long = "loglevel", default_value = format!("info,{}=trace", env!("CARGO_PKG_NAME")))]
+    log_level: String,
+}
+
+#[tokio::main]
+async fn main() {
+    // Get CLI arguments
+    let args = Args::parse();
+
+    // Init logger
+    kaspa_core::log::init_logger(None, &args.log_level);
+
+    // Select network
+    let profile = if args.mainnet { NetworkProfile::MAINNET } else { NetworkProfile::TESTNET_10 };
+    let (network, prefix) = (profile.network_id, profile.prefix());
+
+    // Generate or obtain Kaspa private key
+    let kaspa_signer = if let Some(private_key_hex) = args.kaspa_private_key {
+        let mut private_key_bytes = [0u8; 32];
+        faster_hex::hex_decode(private_key_hex.as_bytes(), &mut private_key_bytes).unwrap();
+        Keypair::from_seckey_slice(secp256k1::SECP256K1, &private_key_bytes).unwrap()
+    } else {
+        let (sk, pk) = &secp256k1::generate_keypair(&mut rand::thread_rng());
+        info!(
+            "Generated private key {} and address {}. Send some funds to this address and rerun with `--kaspa-private-key {}`",
+            sk.display_secret(),
+            String::from(&Address::new(prefix, Version::PubKey, &pk.x_only_public_key().0.serialize())),
+            sk.display_secret()
+        );
+        if let Some(faucet_url) = profile.faucet_url {
+            info!("Testnet funds are available from the faucet at {}", faucet_url);
+        }
+        return;
+    };
+
+    // Extract Kaspa address
+    let kaspa_addr = Address::new(prefix, Version::PubKey, &kaspa_signer.x_only_public_key().0.serialize());
+
+    // Obtain game keys
+    let (sk, player_pk) = if let Some(game_key_hex) = args.game_private_key {
+        let pair = Keypair::from_str(&game_key_hex).unwrap();
+        (pair.secret_key(), PubKey(pair.public_key()))
+    } else {
+        let (sk, pk) = generate_keypair();
+        info!("Player private key: {}", sk.display_secret());
+        (sk, pk)
+    };
+
+    info!("Player public key: {}", player_pk);
+
+    // ... and the other voters
+    let voters: Vec<PubKey> = args.voter.iter().map(|voter_key_hex| PubKey(PublicKey::from_str(voter_key_hex).unwrap())).collect();
+
+    // Connect kaspad clients
+    let kaspad = connect_client_with_failover(network, &args.wrpc_url).await.unwrap();
+    let player_kaspad = connect_client(network, kaspad.url()).await.unwrap(); // Pin to the same node
+
+    // Define channels and exit flag
+    let (sender, receiver) = channel();
+    let (response_sender, response_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let exit_signal = Arc::new(AtomicBool::new(false));
+    let exit_signal_receiver = exit_signal.clone();
+
+    // On Ctrl-C or SIGTERM, stop the listener which in turn signals the engine to exit
+    let interrupted = proxy::exit_on_ctrl_c(exit_signal.clone());
+
+    // Keep episodes for as long as their txs are retained by the node
+    let episode_lifetime = proxy::pruning_window(&kaspad).await.unwrap();
+    info!("Episode lifetime: {} DAA score units", episode_lifetime);
+
+    // Run the engine
+    let config = EngineConfig { episode_lifetime, ..Default::default() };
+    let mut engine = engine::Engine::<Voting, VotingHandler>::with_config(receiver, config);
+    let engine_task = tokio::task::spawn_blocking(move || {
+        engine.start(vec![VotingHandler { sender: response_sender, player: player_pk }]);
+    });
+
+    // Run the player task
+    let fee = args.fee.unwrap_or(profile.default_fee);
+    let generator = generator::TransactionGenerator::new(kaspa_signer, PATTERN, PREFIX);
+    let submitter = CommandSubmitter::new(&player_kaspad, generator, kaspa_addr, profile, fee, !voters.is_empty()).await.unwrap();
+    let player_task = tokio::spawn(async move {
+        play_vote(player_kaspad, submitter, response_receiver, exit_signal, sk, player_pk, voters).await;
+    });
+
+    // Run the kaspad listener
+    let engines = std::iter::once((PREFIX, (PATTERN, sender))).collect();
+    proxy::run_listener(kaspad, network, args.wrpc_url, engines, exit_signal_receiver).await;
+
+    engine_task.await.unwrap();
+    if interrupted.load(Ordering::Relaxed) {
+        // The player task might be blocked on stdin, so exit without awaiting it
+        std::process::exit(0);
+    }
+    player_task.await.unwrap();
+}
+
+const PREFIX: PrefixType = 1448039508;
+const PATTERN: PatternType = generator::pattern_from_prefix(PREFIX);
+const DEADLINE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A game state together with the metadata of the accepted transaction which produced it, if any
+type StateUpdate = (EpisodeId, VotingState, Option<PayloadMetadata>);
+
+struct VotingHandler {
+    sender: UnboundedSender<StateUpdate>,
+    player: PubKey, // The local player pubkey
+}
+
+impl EpisodeEventHandler<Voting> for VotingHandler {
+    fn on_initialize(&self, episode_id: kdapp::episode::EpisodeId, episode: &Voting) {
+        if episode.is_voter(self.player) {
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        } else if episode.voters.contains(&self.player) {
+            warn!("Skipping episode {} with repeated voters {:?}", episode_id, episode.voters);
+        }
+    }
+
+    fn on_command(
+        &self,
+        episode_id: kdapp::episode::EpisodeId,
+        episode: &Voting,
+        _cmd: &<Voting as kdapp::episode::Episode>::Command,
+        _authorization: Option<PubKey>,
+        metadata: &kdapp::episode::PayloadMetadata,
+    ) {
+        if episode.is_voter(self.player) {
+            let _ = self.sender.send((episode_id, episode.poll(), Some(metadata.clone())));
+        }
+    }
+
+    fn on_rollback(&self, episode_id: kdapp::episode::EpisodeId, episode: &Voting, metadata: &kdapp::episode::PayloadMetadata) {
+        if episode.is_voter(self.player) {
+            warn!("A DAG reorg reverted tx {} in episode {}", metadata.tx_id, episode_id);
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+
+    fn on_reject(
+        &self,
+        episode_id: kdapp::episode::EpisodeId,
+        episode: &Voting,
+        cmd: &<Voting as kdapp::episode::Episode>::Command,
+        authorization: Option<PubKey>,
+        error: &EpisodeError<<Voting as kdapp::episode::Episode>::CommandError>,
+        _metadata: &kdapp::episode::PayloadMetadata,
+    ) {
+        if authorization == Some(self.player) && error.is_authenticated() {
+            println!("Your command {:?} was rejected: {}", cmd, error);
+            // Wake the voter task, which would otherwise keep waiting for the command to take effect
+            let _ = self.sender.send((episode_id, episode.poll(), None));
+        }
+    }
+}
+
+/// Prints a vote state along with when and where the transaction producing it was accepted
+fn print_update(state: &VotingState, metadata: &Option<PayloadMetadata>) {
+    state.print();
+    if let Some(metadata) = metadata {
+        println!("{}", utils::format_acceptance(metadata));
+    }
+}
+
+/// Follows updates of the episode until `done` holds for its state and the virtual DAA score. Returns false if the
+/// engine exited first
+async fn wait_until(
+    kaspad: &KaspaRpcClient,
+    response_receiver: &mut UnboundedReceiver<StateUpdate>,
+    episode_id: EpisodeId,
+    state: &mut VotingState,
+    done: impl Fn(&VotingState, u64) -> bool,
+) -> bool {
+    loop {
+        tokio::select! {
+            update = response_receiver.recv() => {
+                let Some((received_id, new_state, metadata)) = update else {
+                    return false;
+                };
+                if received_id == episode_id {
+                    *state = new_state;
+                    print_update(state, &metadata);
+                }
+            }
+            _ = tokio::time::sleep(DEADLINE_POLL_INTERVAL) => {
+                if proxy::virtual_daa_score(kaspad).await.is_some_and(|daa| done(state, daa)) {
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+async fn play_vote(
+    kaspad: KaspaRpcClient,
+    mut submitter: CommandSubmitter,
+    mut response_receiver: UnboundedReceiver<StateUpdate>,
+    exit_signal: Arc<AtomicBool>,
+    sk: SecretKey,
+    player_pk: PubKey,
+    voters: Vec<PubKey>,
+) {
+    // When voters are passed, we are expected to open the vote
+    if !voters.is_empty() {
+        let episode_id = rand::thread_rng().gen();
+        let participants = std::iter::once(player_pk).chain(voters).collect();
+        let new_episode = EpisodeMessage::<Voting>::NewEpisode { episode_id, participants, params: () };
+        submitter.submit(&kaspad, &new_episode).await.unwrap();
+    }
+
+    // A closed channel means the engine has exited
+    let Some((episode_id, mut state, metadata)) = response_receiver.recv().await else {
+        return;
+    };
+    print_update(&state, &metadata);
+
+    let mut input = String::new();
+    let vote = loop {
+        input.clear();
+        println!("Cast your vote before DAA score {}: [y]es, [n]o or [a]bstain", state.voting_ends);
+        std::io::stdin().read_line(&mut input).unwrap();
+        match input.trim() {
+            "y" | "yes" => break Vote::Yes,
+            "n" | "no" => break Vote::No,
+            "a" | "abstain" => break Vote::Abstain,
+            _ => println!("Invalid input, expected: y, n or a"),
+        }
+    };
+    // Only the salted hash goes on-chain until voting closes
+    let salt = rand::thread_rng().gen();
+    let step = EpisodeMessage::<Voting>::new_signed_command(
+        episode_id,
+        VotingCommand::Cast(game::ballot(player_pk, vote, &salt)),
+        sk,
+        player_pk,
+    );
+    submitter.submit(&kaspad, &step).await.unwrap();
+
+    println!("Waiting for voting to close before revealing");
+    if !wait_until(&kaspad, &mut response_receiver, episode_id, &mut state, |state, daa| daa > state.voting_ends).await {
+        return;
+    }
+    let step = EpisodeMessage::<Voting>::new_signed_command(episode_id, VotingCommand::Reveal { vote, salt }, sk, player_pk);
+    submitter.submit(&kaspad, &step).await.unwrap();
+
+    if !wait_until(&kaspad, &mut response_receiver, episode_id, &mut state, |state, daa| state.is_final(daa)).await {
+        return;
+    }
+    println!("---- Final tally ----");
+    state.print();
+    exit_signal.store(true, Ordering::Relaxed);
+}
@@ -7,13 +7,19 @@ use log::*;
 use secp256k1::SecretKey;
 
 use crate::episode::{Episode, EpisodeError, EpisodeEventHandler, EpisodeId, PayloadMetadata};
+use crate::metrics::EngineMetrics;
 use crate::pki::{sign_message, to_message, verify_signature, PubKey, Sig};
 use std::any::type_name;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::sync::oneshot;
 
 const EPISODE_LIFETIME: u64 = 2592000; // Three days
 const SAMPLE_REMOVAL_TIME: u64 = 432000; // Half a day
@@ -23,6 +29,24 @@ pub(crate) struct EpisodeWrapper<G: Episode> {
     pub rollback_stack: Vec<G::CommandRollback>,
 }
 
+/// Tunable engine parameters
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    /// Maximum number of concurrently running episodes. Once reached, new episodes are rejected until
+    /// running ones expire. Running episodes are never evicted to make room, since anyone can publish new
+    /// episode txs and would otherwise be able to end other participants' episodes at the cost of a fee
+    pub max_episodes: Option<usize>,
+    /// Episode lifetime in DAA score units, measured from episode creation. See [`crate::proxy::pruning_window`]
+    /// for deriving it from the connected node
+    pub episode_lifetime: u64,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self { max_episodes: None, episode_lifetime: EPISODE_LIFETIME }
+    }
+}
+
 #[derive(Default)]
 pub struct DefaultEventHandler;
 
@@ -39,7 +63,7 @@ impl<G: Episode> EpisodeEventHandler<G> for DefaultEventHandler {
     ) {
     }
 
-    fn on_rollback(&self, _episode_id: EpisodeId, _episode: &G) {}
+    fn on_rollback(&self, _episode_id: EpisodeId, _episode: &G, _metadata: &PayloadMetadata) {}
 }
 
 /// The main entry point for running episodes of a given Episode type.
@@ -49,13 +73,15 @@ pub struct Engine<G: Episode, P: EpisodeEventHandler<G> = DefaultEventHandler> {
     pub(crate) receiver: Receiver<EngineMsg>,
     pub(crate) next_filtering: u64,
     pub(crate) episode_creation_times: HashMap<EpisodeId, u64>,
+    pub(crate) config: EngineConfig,
+    pub(crate) metrics: Arc<EngineMetrics>,
 
     _phantom: PhantomData<P>,
 }
 
 #[derive(Debug, BorshSerialize, BorshDeserialize)]
 pub enum EpisodeMessage<G: Episode> {
-    NewEpisode { episode_id: EpisodeId, participants: Vec<PubKey> },
+    NewEpisode { episode_id: EpisodeId, participants: Vec<PubKey>, params: G::InitParams },
     SignedCommand { episode_id: EpisodeId, cmd: G::Command, pubkey: PubKey, sig: Sig },
     UnsignedCommand { episode_id: EpisodeId, cmd: G::Command },
     Revert { episode_id: EpisodeId },
@@ -78,16 +104,43 @@ impl<G: Episode> EpisodeMessage<G> {
     }
 }
 
-#[derive(Debug, BorshSerialize, BorshDeserialize)]
+/// A point-in-time capture of a single episode's serialized state. Rollback objects are not captured,
+/// hence a restored episode starts with a fresh rollback stack.
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct EpisodeSnapshot {
+    pub episode_id: EpisodeId,
+    pub creation_daa: u64,
+    pub state: Vec<u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("episode {0} already exists.")]
+    EpisodeExists(EpisodeId),
+
+    #[error("episode capacity ({0}) reached.")]
+    CapacityReached(usize),
+
+    #[error("failed to decode episode state: {0}")]
+    Decode(#[from] std::io::Error),
+}
+
+/// Messages driving the engine loop. Sync, snapshot and restore requests are answered on their reply channel once all
+/// messages sent to the engine before them are processed
+#[derive(Debug)]
 pub enum EngineMsg {
     BlkAccepted { accepting_hash: Hash, accepting_daa: u64, accepting_time: u64, associated_txs: Vec<(Hash, Vec<u8>)> },
     BlkReverted { accepting_hash: Hash },
+    Tick { daa_score: u64 },
+    Snapshot { episode_id: EpisodeId, reply: oneshot::Sender<Option<EpisodeSnapshot>> },
+    Restore { snapshot: EpisodeSnapshot, episode_id: EpisodeId, reply: oneshot::Sender<Result<(), SnapshotError>> },
+    Sync { reply: oneshot::Sender<()> },
     Exit,
 }
 
 impl<G: Episode> EpisodeWrapper<G> {
-    pub fn initialize(participants: Vec<PubKey>, metadata: &PayloadMetadata) -> Self {
-        let episode = G::initialize(participants, metadata);
+    pub fn initialize(participants: Vec<PubKey>, params: &G::InitParams, metadata: &PayloadMetadata) -> Self {
+        let episode = G::initialize(participants, params, metadata);
         let rollback_stack = vec![];
         EpisodeWrapper { episode, rollback_stack }
     }
@@ -132,70 +185,46 @@ impl<G: Episode> EpisodeWrapper<G> {
 
 impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
     pub fn new(receiver: Receiver<EngineMsg>) -> Self {
+        Self::with_config(receiver, EngineConfig::default())
+    }
+
+    pub fn with_config(receiver: Receiver<EngineMsg>, config: EngineConfig) -> Self {
         let episodes: HashMap<EpisodeId, EpisodeWrapper<G>> = HashMap::new();
         let episode_creation_times: HashMap<EpisodeId, u64> = HashMap::new();
         let revert_map: HashMap<Hash, Vec<(EpisodeId, PayloadMetadata)>> = HashMap::new();
         let next_filtering: u64 = 0;
-        Self { episodes, revert_map, episode_creation_times, receiver, next_filtering, _phantom: Default::default() }
+        let metrics = Arc::new(EngineMetrics::default());
+        Self { episodes, revert_map, episode_creation_times, receiver, next_filtering, config, metrics, _phantom: Default::default() }
     }
 
-    pub fn start(&mut self, handlers: Vec<H>) {
-        while let Ok(msg) = self.receiver.recv() {
-            match msg {
-                EngineMsg::BlkAccepted { accepting_hash, accepting_daa, accepting_time, associated_txs } => {
-                    self.filter_old_episodes(accepting_daa);
-                    let mut revert_vec: Vec<(EpisodeId, PayloadMetadata)> = vec![];
-                    for (tx_id, payload) in associated_txs {
-                        let episode_action: EpisodeMessage<G> = match borsh::from_slice(&payload) {
-                            Ok(EpisodeMessage::Revert { episode_id }) => {
-                                warn!("Episode: {}. Illegal revert attempted. Ignoring.", episode_id);
-                                continue;
-                            }
-                            Ok(episode_action) => episode_action,
-                            Err(err) => {
-                                warn!("Payload: {:?} rejected. Parsing error: {}", payload, err);
-                                continue;
-                            }
-                        };
-                        let metadata = PayloadMetadata { accepting_hash, accepting_daa, accepting_time, tx_id };
-                        if let Some(revert_id) = self.handle_message(episode_action, &metadata, &handlers) {
-                            revert_vec.push(revert_id);
-                        }
-                    }
-                    self.revert_map.insert(accepting_hash, revert_vec);
-                }
-                EngineMsg::BlkReverted { accepting_hash } => match self.revert_map.entry(accepting_hash) {
-                    Entry::Occupied(entry) => {
-                        for reversion in entry.remove().into_iter().rev() {
-                            let episode_action: EpisodeMessage<G> = EpisodeMessage::Revert { episode_id: reversion.0 };
-                            let metadata = PayloadMetadata {
-                                accepting_hash,
-                                accepting_daa: reversion.1.accepting_daa,
-                                accepting_time: reversion.1.accepting_time,
-                                tx_id: reversion.1.tx_id,
-                            };
-                            assert_eq!(self.handle_message(episode_action, &metadata, &handlers), None);
-                        }
-                    }
-                    Entry::Vacant(_) => {}
-                },
-                EngineMsg::Exit => break,
-            }
-        }
+    /// Returns a shared handle to the engine counters, which can be sampled while the engine is running
+    pub fn metrics(&self) -> Arc<EngineMetrics> {
+        self.metrics.clone()
+    }
+
+    fn update_active_episodes(&self) {
+        self.metrics.active_episodes.store(self.episodes.len() as u64, Ordering::Relaxed);
     }
 
-    pub fn filter_old_episodes(&mut self, daa_score: u64) {
+    pub fn filter_old_episodes(&mut self, daa_score: u64, handlers: &[H]) {
         if daa_score > self.next_filtering + SAMPLE_REMOVAL_TIME {
             let mut remove_ids = vec![];
             for (episode_id, creation_time) in self.episode_creation_times.iter() {
-                if creation_time < &daa_score.saturating_sub(EPISODE_LIFETIME) {
+                if creation_time < &daa_score.saturating_sub(self.config.episode_lifetime) {
                     remove_ids.push(*episode_id);
                 }
             }
             for episode_id in remove_ids {
-                self.episodes.remove_entry(&episode_id);
+                if let Some(wrapper) = self.episodes.remove(&episode_id) {
+                    for handler in handlers.iter() {
+                        handler.on_expire(episode_id, &wrapper.episode);
+                    }
+                    self.metrics.episodes_expired.fetch_add(1, Ordering::Relaxed);
+                    debug!("Episode {} expired.", episode_id);
+                }
                 self.episode_creation_times.remove_entry(&episode_id);
             }
+            self.update_active_episodes();
             self.next_filtering = daa_score;
         }
     }
@@ -207,25 +236,35 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
         handlers: &[H],
     ) -> Option<(EpisodeId, PayloadMetadata)> {
         match episode_action {
-            EpisodeMessage::NewEpisode { episode_id, participants } => {
+            EpisodeMessage::NewEpisode { episode_id, participants, params } => {
                 if self.episodes.contains_key(&episode_id) {
                     warn!("Episode with id {} already exists", episode_id);
                     return None;
                 }
-                let ew = EpisodeWrapper::<G>::initialize(participants, metadata);
+                if let Some(max_episodes) = self.config.max_episodes.filter(|&max| self.episodes.len() >= max) {
+                    warn!("Episode capacity ({}) reached. Rejecting new episode {}", max_episodes, episode_id);
+                    self.metrics.episodes_rejected.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                let ew = EpisodeWrapper::<G>::initialize(participants, &params, metadata);
                 for handler in handlers.iter() {
                     handler.on_initialize(episode_id, &ew.episode);
                 }
                 self.episodes.insert(episode_id, ew);
                 debug!("Episode {} created.", episode_id);
                 self.episode_creation_times.insert(episode_id, metadata.accepting_daa);
+                self.metrics.episodes_created.fetch_add(1, Ordering::Relaxed);
+                self.update_active_episodes();
 
                 return Some((episode_id, metadata.clone()));
             }
 
             EpisodeMessage::SignedCommand { episode_id, cmd, pubkey, sig } => {
                 if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
-                    match wrapper.execute_signed(&cmd, pubkey, sig, metadata) {
+                    let start = Instant::now();
+                    let res = wrapper.execute_signed(&cmd, pubkey, sig, metadata);
+                    self.metrics.record_command(&res, start);
+                    match res {
                         Ok(()) => {
                             for handler in handlers.iter() {
                                 handler.on_command(episode_id, &wrapper.episode, &cmd, Some(pubkey), metadata);
@@ -233,7 +272,10 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
                             return Some((episode_id, metadata.clone()));
                         }
                         Err(e) => {
-                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e)
+                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e);
+                            for handler in handlers.iter() {
+                                handler.on_reject(episode_id, &wrapper.episode, &cmd, Some(pubkey), &e, metadata);
+                            }
                         }
                     }
                 } else {
@@ -243,7 +285,10 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
 
             EpisodeMessage::UnsignedCommand { episode_id, cmd } => {
                 if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
-                    match wrapper.execute_unsigned(&cmd, metadata) {
+                    let start = Instant::now();
+                    let res = wrapper.execute_unsigned(&cmd, metadata);
+                    self.metrics.record_command(&res, start);
+                    match res {
                         Ok(()) => {
                             for handler in handlers.iter() {
                                 handler.on_command(episode_id, &wrapper.episode, &cmd, None, metadata);
@@ -251,7 +296,10 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
                             return Some((episode_id, metadata.clone()));
                         }
                         Err(e) => {
-                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e)
+                            warn!("Episode {}: Command {:?} rejected: {}", episode_id, cmd, e);
+                            for handler in handlers.iter() {
+                                handler.on_reject(episode_id, &wrapper.episode, &cmd, None, &e, metadata);
+                            }
                         }
                     }
                 } else {
@@ -263,13 +311,17 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
                 if let Some(wrapper) = self.episodes.get_mut(&episode_id) {
                     info!("Episode {}: Reverting command: {:?}", episode_id, metadata.tx_id);
                     let rollback_result = wrapper.rollback();
+                    if rollback_result.is_ok() {
+                        self.metrics.rollbacks.fetch_add(1, Ordering::Relaxed);
+                    }
                     for handler in handlers.iter() {
-                        handler.on_rollback(episode_id, &wrapper.episode);
+                        handler.on_rollback(episode_id, &wrapper.episode, metadata);
                     }
                     if let Err(EpisodeError::DeleteEpisode) = rollback_result {
                         // A revert of the creation
                         self.episodes.remove_entry(&episode_id);
                         self.episode_creation_times.remove_entry(&episode_id);
+                        self.update_active_episodes();
                     }
                 } else {
                     warn!("Episode {} not found.", episode_id);
@@ -280,3 +332,90 @@ impl<G: Episode, H: EpisodeEventHandler<G>> Engine<G, H> {
         None
     }
 }
+
+impl<G: Episode + BorshSerialize + BorshDeserialize, H: EpisodeEventHandler<G>> Engine<G, H> {
+    /// Runs the engine loop until an exit message is received or all senders are dropped. Episode state is
+    /// accessible while running through [`EngineMsg::Snapshot`] and [`EngineMsg::Restore`], hence the serialization bounds
+    pub fn start(&mut self, handlers: Vec<H>) {
+        while let Ok(msg) = self.receiver.recv() {
+            self.metrics.messages_processed.fetch_add(1, Ordering::Relaxed);
+            match msg {
+                EngineMsg::BlkAccepted { accepting_hash, accepting_daa, accepting_time, associated_txs } => {
+                    self.filter_old_episodes(accepting_daa, &handlers);
+                    let mut revert_vec: Vec<(EpisodeId, PayloadMetadata)> = vec![];
+                    for (tx_id, payload) in associated_txs {
+                        let episode_action: EpisodeMessage<G> = match borsh::from_slice(&payload) {
+                            Ok(EpisodeMessage::Revert { episode_id }) => {
+                                warn!("Episode: {}. Illegal revert attempted. Ignoring.", episode_id);
+                                continue;
+                            }
+                            Ok(episode_action) => episode_action,
+                            Err(err) => {
+                                warn!("Payload: {:?} rejected. Parsing error: {}", payload, err);
+                                continue;
+                            }
+                        };
+                        let metadata = PayloadMetadata { accepting_hash, accepting_daa, accepting_time, tx_id };
+                        if let Some(revert_id) = self.handle_message(episode_action, &metadata, &handlers) {
+                            revert_vec.push(revert_id);
+                        }
+                    }
+                    self.revert_map.insert(accepting_hash, revert_vec);
+                }
+                EngineMsg::BlkReverted { accepting_hash } => match self.revert_map.entry(accepting_hash) {
+                    Entry::Occupied(entry) => {
+                        for reversion in entry.remove().into_iter().rev() {
+                            let episode_action: EpisodeMessage<G> = EpisodeMessage::Revert { episode_id: reversion.0 };
+                            let metadata = PayloadMetadata {
+                                accepting_hash,
+                                accepting_daa: reversion.1.accepting_daa,
+                                accepting_time: reversion.1.accepting_time,
+                                tx_id: reversion.1.tx_id,
+                            };
+                            assert_eq!(self.handle_message(episode_action, &metadata, &handlers), None);
+                        }
+                    }
+                    Entry::Vacant(_) => {}
+                },
+                EngineMsg::Tick { daa_score } => self.filter_old_episodes(daa_score, &handlers),
+                EngineMsg::Snapshot { episode_id, reply } => {
+                    // The requester might have given up waiting, which is not the engine's concern
+                    let _ = reply.send(self.snapshot(episode_id));
+                }
+                EngineMsg::Restore { snapshot, episode_id, reply } => {
+                    let _ = reply.send(self.restore(&snapshot, episode_id));
+                }
+                EngineMsg::Sync { reply } => {
+                    let _ = reply.send(());
+                }
+                EngineMsg::Exit => break,
+            }
+        }
+    }
+
+    /// Capture the current state of an episode along with its creation DAA score
+    pub fn snapshot(&self, episode_id: EpisodeId) -> Option<EpisodeSnapshot> {
+        let wrapper = self.episodes.get(&episode_id)?;
+        let creation_daa = self.episode_creation_times.get(&episode_id).copied().unwrap_or_default();
+        let state = borsh::to_vec(&wrapper.episode).expect("serialization failed");
+        Some(EpisodeSnapshot { episode_id, creation_daa, state })
+    }
+
+    /// Restore a previously captured snapshot into `episode_id`, which must not be in use. The restored
+    /// episode keeps the creation DAA score of the original and is thus subject to the same lifetime, and
+    /// counts towards `EngineConfig::max_episodes` like any new episode.
+    pub fn restore(&mut self, snapshot: &EpisodeSnapshot, episode_id: EpisodeId) -> Result<(), SnapshotError> {
+        if self.episodes.contains_key(&episode_id) {
+            return Err(SnapshotError::EpisodeExists(episode_id));
+        }
+        if let Some(max_episodes) = self.config.max_episodes.filter(|&max| self.episodes.len() >= max) {
+            return Err(SnapshotError::CapacityReached(max_episodes));
+        }
+        let episode: G = borsh::from_slice(&snapshot.state)?;
+        self.episodes.insert(episode_id, EpisodeWrapper { episode, rollback_stack: vec![] });
+        self.episode_creation_times.insert(episode_id, snapshot.creation_daa);
+        self.update_active_episodes();
+        info!("Episode {} restored from snapshot of episode {}.", episode_id, snapshot.episode_id);
+        Ok(())
+    }
+}
@@ -22,6 +22,14 @@ pub enum EpisodeError<E: Error + 'static> {
     DeleteEpisode,
 }
 
+impl<E: Error + 'static> EpisodeError<E> {
+    /// Whether the rejected command was signed by its claimed author. After a failed signature the pubkey is merely claimed,
+    /// possibly by a forgery, so the author's own command may still be pending
+    pub fn is_authenticated(&self) -> bool {
+        !matches!(self, EpisodeError::InvalidSignature)
+    }
+}
+
 #[derive(Clone, PartialEq, Debug, BorshSerialize, BorshDeserialize)]
 pub struct PayloadMetadata {
     pub accepting_hash: Hash,
@@ -36,9 +44,13 @@ pub trait Episode {
     type Command: BorshSerialize + BorshDeserialize + Debug + Clone;
     type CommandRollback: BorshSerialize + BorshDeserialize;
     type CommandError: Error + 'static;
+    /// Parameters chosen by the episode creator and carried by the new episode message, e.g. rule
+    /// variants or board dimensions. Episodes without such choices can use `()`
+    type InitParams: BorshSerialize + BorshDeserialize + Debug + Clone;
 
-    /// Initialize the episode, possibly providing a set of authorized pubkey participants
-    fn initialize(participants: Vec<PubKey>, metadata: &PayloadMetadata) -> Self;
+    /// Initialize the episode, possibly providing a set of authorized pubkey participants. Params come
+    /// straight from the network, so implementations should bound or sanitize them
+    fn initialize(participants: Vec<PubKey>, params: &Self::InitParams, metadata: &PayloadMetadata) -> Self;
 
     /// Execute a command advancing the state of the episode, possibly attaching the already verified
     /// authorized pubkey requesting this execution. Returns a rollback object which can be used later
@@ -68,6 +80,24 @@ pub trait EpisodeEventHandler<G: Episode> {
         metadata: &PayloadMetadata,
     );
 
-    /// Called by the engine following a command rollback
-    fn on_rollback(&self, episode_id: EpisodeId, episode: &G);
+    /// Called by the engine following a command rollback due to a DAG reorg. The metadata describes the
+    /// reverted tx and its original acceptance
+    fn on_rollback(&self, episode_id: EpisodeId, episode: &G, metadata: &PayloadMetadata);
+
+    /// Called by the engine when a command is rejected, either by the episode or for failing signature
+    /// verification, in which case the authorization is the claimed and unverified pubkey. Allows reporting to
+    /// the submitting participant why their command had no effect
+    fn on_reject(
+        &self,
+        _episode_id: EpisodeId,
+        _episode: &G,
+        _cmd: &G::Command,
+        _authorization: Option<PubKey>,
+        _error: &EpisodeError<G::CommandError>,
+        _metadata: &PayloadMetadata,
+    ) {
+    }
+
+    /// Called by the engine right before an episode is dropped due to expiration, allowing its final state to be archived
+    fn on_expire(&self, _episode_id: EpisodeId, _episode: &G) {}
 }
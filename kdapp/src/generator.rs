@@ -32,6 +32,31 @@ pub fn check_pattern(tx_id: Hash, pattern: &PatternType) -> bool {
     true
 }
 
+/// Derives a deterministic tx id pattern from a prefix by using the prefix as a seed for choosing 10 distinct
+/// bit positions and their expected values. Deriving the pattern this way ensures that a generator and a
+/// listener configured with the same prefix always agree on the pattern.
+pub const fn pattern_from_prefix(prefix: PrefixType) -> PatternType {
+    let mut pattern = [(0u8, 0u8); 10];
+    let mut used = [false; 256];
+    let mut state = prefix as u64;
+    let mut count = 0;
+    while count < pattern.len() {
+        // A splitmix64 step
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        let pos = z as u8;
+        if !used[pos as usize] {
+            used[pos as usize] = true;
+            pattern[count] = (pos, ((z >> 8) & 1) as u8);
+            count += 1;
+        }
+    }
+    pattern
+}
+
 pub struct Payload;
 
 impl Payload {
@@ -119,3 +144,28 @@ impl TransactionGenerator {
 pub fn get_first_output_utxo(tx: &Transaction) -> (TransactionOutpoint, UtxoEntry) {
     (TransactionOutpoint::new(tx.id(), 0), UtxoEntry::new(tx.outputs[0].value, tx.outputs[0].script_public_key.clone(), 0, false))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_pattern_from_prefix() {
+        let pattern = pattern_from_prefix(858598618);
+        assert_eq!(pattern, pattern_from_prefix(858598618));
+        assert_ne!(pattern, pattern_from_prefix(858598619));
+        assert_eq!(pattern.iter().map(|(pos, _)| pos).collect::<HashSet<_>>().len(), pattern.len());
+        assert!(pattern.iter().all(|&(_, val)| val <= 1));
+
+        // Build an id satisfying the pattern and make sure it is matched
+        let mut bytes = [0u8; 32];
+        for (pos, val) in pattern {
+            bytes[pos as usize / 8] |= val << (pos % 8);
+        }
+        assert!(check_pattern(Hash::from_bytes(bytes), &pattern));
+        let (pos, _) = pattern[0];
+        bytes[pos as usize / 8] ^= 1 << (pos % 8);
+        assert!(!check_pattern(Hash::from_bytes(bytes), &pattern));
+    }
+}
@@ -1,5 +1,7 @@
 pub mod engine;
 pub mod episode;
 pub mod generator;
+pub mod metrics;
 pub mod pki;
 pub mod proxy;
+pub mod utils;
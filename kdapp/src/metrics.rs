@@ -0,0 +1,102 @@
+//! Lightweight atomic counters for observing engine and listener activity. Counters are shared through
+//! an `Arc` so they can be sampled from other threads while the engine or listener is running.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+#[derive(Debug, Default)]
+pub struct EngineMetrics {
+    pub messages_processed: AtomicU64,
+    pub active_episodes: AtomicU64,
+    pub episodes_created: AtomicU64,
+    pub episodes_expired: AtomicU64,
+    /// New episodes dropped because the engine was running at capacity
+    pub episodes_rejected: AtomicU64,
+    pub commands_executed: AtomicU64,
+    pub commands_rejected: AtomicU64,
+    pub rollbacks: AtomicU64,
+    /// Cumulative execution time of all executed commands, in microseconds
+    pub command_exec_micros: AtomicU64,
+}
+
+impl EngineMetrics {
+    pub(crate) fn record_command<E>(&self, result: &Result<(), E>, start: Instant) {
+        if result.is_ok() {
+            self.commands_executed.fetch_add(1, Ordering::Relaxed);
+            self.command_exec_micros.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        } else {
+            self.commands_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> EngineMetricsSnapshot {
+        EngineMetricsSnapshot {
+            messages_processed: self.messages_processed.load(Ordering::Relaxed),
+            active_episodes: self.active_episodes.load(Ordering::Relaxed),
+            episodes_created: self.episodes_created.load(Ordering::Relaxed),
+            episodes_expired: self.episodes_expired.load(Ordering::Relaxed),
+            episodes_rejected: self.episodes_rejected.load(Ordering::Relaxed),
+            commands_executed: self.commands_executed.load(Ordering::Relaxed),
+            commands_rejected: self.commands_rejected.load(Ordering::Relaxed),
+            rollbacks: self.rollbacks.load(Ordering::Relaxed),
+            command_exec_micros: self.command_exec_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EngineMetricsSnapshot {
+    pub messages_processed: u64,
+    pub active_episodes: u64,
+    pub episodes_created: u64,
+    pub episodes_expired: u64,
+    pub episodes_rejected: u64,
+    pub commands_executed: u64,
+    pub commands_rejected: u64,
+    pub rollbacks: u64,
+    pub command_exec_micros: u64,
+}
+
+impl EngineMetricsSnapshot {
+    /// Average command execution time in microseconds
+    pub fn avg_command_exec_micros(&self) -> u64 {
+        self.command_exec_micros.checked_div(self.commands_executed).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ListenerMetrics {
+    pub chain_blocks_added: AtomicU64,
+    pub chain_blocks_removed: AtomicU64,
+    /// Number of blocks fetched from the node in order to obtain tx payloads
+    pub blocks_fetched: AtomicU64,
+    /// Accepted txs matching the id pattern of at least one engine
+    pub pattern_matches: AtomicU64,
+    /// Payloads matching an engine prefix and forwarded to it
+    pub payloads_forwarded: AtomicU64,
+    /// Messages sent to engines. Compared with [`EngineMetrics::messages_processed`] this indicates the engine queue depth
+    pub messages_sent: AtomicU64,
+}
+
+impl ListenerMetrics {
+    pub fn snapshot(&self) -> ListenerMetricsSnapshot {
+        ListenerMetricsSnapshot {
+            chain_blocks_added: self.chain_blocks_added.load(Ordering::Relaxed),
+            chain_blocks_removed: self.chain_blocks_removed.load(Ordering::Relaxed),
+            blocks_fetched: self.blocks_fetched.load(Ordering::Relaxed),
+            pattern_matches: self.pattern_matches.load(Ordering::Relaxed),
+            payloads_forwarded: self.payloads_forwarded.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenerMetricsSnapshot {
+    pub chain_blocks_added: u64,
+    pub chain_blocks_removed: u64,
+    pub blocks_fetched: u64,
+    pub pattern_matches: u64,
+    pub payloads_forwarded: u64,
+    pub messages_sent: u64,
+}
@@ -1,7 +1,12 @@
 //! Contains methods for creating a Kaspa wrpc client as well as listener logic for following
 //! accepted txs by id pattern and prefix and sending them to corresponding engines.
 
-use kaspa_consensus_core::{network::NetworkId, Hash};
+use kaspa_addresses::Address;
+use kaspa_consensus_core::{
+    network::NetworkId,
+    tx::{Transaction, TransactionOutpoint, UtxoEntry},
+    Hash,
+};
 use kaspa_rpc_core::api::rpc::RpcApi;
 use kaspa_rpc_core::RpcNetworkType;
 use kaspa_wrpc_client::client::ConnectOptions;
@@ -18,12 +23,16 @@ use std::sync::{
     Arc,
 };
 use std::time::Duration;
+use tokio::sync::oneshot;
 use tokio::time::{sleep_until, Instant};
 
-use crate::generator::{PatternType, PrefixType};
+use crate::generator::{get_first_output_utxo, PatternType, PrefixType, TransactionGenerator};
+use crate::metrics::ListenerMetrics;
 use crate::{
-    engine::EngineMsg as Msg,
+    engine::{EngineMsg as Msg, EpisodeMessage},
+    episode::Episode,
     generator::{check_pattern, Payload},
+    utils::{self, network::NetworkProfile},
 };
 
 fn connect_options() -> ConnectOptions {
@@ -68,13 +77,236 @@ pub async fn connect_client(network_id: NetworkId, rpc_url: Option<String>) -> R
     }
 }
 
+/// Connects to the first responsive and synced node out of `rpc_urls`, in order, or to a resolver (PNN) node if the list
+/// is empty. Additional clients can be pinned to the selected node via [`KaspaRpcClient::url`], avoiding propagation
+/// races between submitting and listening. The listener fails over through the same list when its node stops
+/// responding, see [`run_listener_from`].
+pub async fn connect_client_with_failover(network_id: NetworkId, rpc_urls: &[String]) -> Result<KaspaRpcClient, Error> {
+    let mut last_err = None;
+    for url in rpc_urls {
+        match connect_client(network_id, Some(url.clone())).await {
+            Ok(client) => return Ok(client),
+            Err(err) => {
+                warn!("Failed connecting to {}: {}", url, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    match last_err {
+        Some(err) => Err(err),
+        None => connect_client(network_id, None).await,
+    }
+}
+
+/// Queries the node for the DAA score distance between the virtual and the pruning point. This approximates the
+/// window within which accepted episode txs can still be obtained from the node, and hence a natural episode lifetime.
+pub async fn pruning_window(kaspad: &KaspaRpcClient) -> Result<u64, Error> {
+    let info = kaspad.get_block_dag_info().await?;
+    let pruning_point = kaspad.get_block(info.pruning_point_hash, false).await?;
+    Ok(info.virtual_daa_score.saturating_sub(pruning_point.header.daa_score))
+}
+
+/// Queries the virtual DAA score, which bounds the accepting DAA score of any tx submitted now from below, e.g. for
+/// checking whether an episode deadline has passed. Failures are logged and yield `None`
+pub async fn virtual_daa_score(kaspad: &KaspaRpcClient) -> Option<u64> {
+    match kaspad.get_block_dag_info().await {
+        Ok(info) => Some(info.virtual_daa_score),
+        Err(err) => {
+            warn!("Failed to query the virtual DAA score: {}", err);
+            None
+        }
+    }
+}
+
+const SUBMIT_ATTEMPTS: u32 = 5;
+
+/// How the node answered a failed submission, judged by the error message since rejections reach wRPC clients as text
+#[derive(Debug, PartialEq, Eq)]
+enum SubmitFailure {
+    /// The tx is already in the mempool or accepted, e.g. since the response to an earlier attempt was lost
+    Duplicate,
+    /// The tx spends outputs unknown to the node, e.g. since the tx creating them is still propagating, so it may be
+    /// accepted once they arrive
+    Orphan,
+    /// The node rejected the tx by consensus or mempool rules, which retrying cannot change
+    Rejected,
+    /// The request did not get a verdict from the node, e.g. due to a dropped connection or a timeout
+    Transport,
+}
+
+impl SubmitFailure {
+    fn classify(err_msg: &str) -> Self {
+        if err_msg.contains("already in the mempool") || err_msg.contains("already accepted") {
+            SubmitFailure::Duplicate
+        } else if err_msg.contains("orphan") {
+            SubmitFailure::Orphan
+        } else if err_msg.contains("Rejected transaction") {
+            SubmitFailure::Rejected
+        } else {
+            SubmitFailure::Transport
+        }
+    }
+}
+
+/// Submits a transaction, retrying transport failures and orphan rejections with exponential backoff in order to survive
+/// transient node disconnections and propagation delays. A tx the node reports as already known counts as submitted
+pub async fn submit_transaction(kaspad: &KaspaRpcClient, tx: &Transaction) -> Result<RpcTransactionId, Error> {
+    let mut backoff = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        let err = match kaspad.submit_transaction(tx.into(), false).await {
+            Ok(tx_id) => return Ok(tx_id),
+            Err(err) => err,
+        };
+        match SubmitFailure::classify(&err.to_string()) {
+            SubmitFailure::Duplicate => {
+                debug!("Tx {} is already known to the node: {}", tx.id(), err);
+                return Ok(tx.id());
+            }
+            SubmitFailure::Orphan | SubmitFailure::Transport if attempt < SUBMIT_ATTEMPTS => {
+                warn!("Submitting {} failed (attempt {}/{}): {}. Retrying in {:?}", tx.id(), attempt, SUBMIT_ATTEMPTS, err, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            _ => return Err(err.into()),
+        }
+    }
+}
+
+/// Submits the episode messages of a single participant, funding each tx by the change output of the previous one
+pub struct CommandSubmitter {
+    generator: TransactionGenerator,
+    kaspa_addr: Address,
+    profile: NetworkProfile,
+    fee: u64,
+    utxo: (TransactionOutpoint, UtxoEntry),
+}
+
+impl CommandSubmitter {
+    /// Funds the txs from a utxo of `kaspa_addr`. The initiator of an episode takes the first utxo and other
+    /// participants the last one, which avoids collisions when players share an address
+    pub async fn new(
+        kaspad: &KaspaRpcClient,
+        generator: TransactionGenerator,
+        kaspa_addr: Address,
+        profile: NetworkProfile,
+        fee: u64,
+        initiator: bool,
+    ) -> Result<Self, Error> {
+        let entries = kaspad.get_utxos_by_addresses(vec![kaspa_addr.clone()]).await?;
+        let entry = if initiator { entries.into_iter().next() } else { entries.into_iter().last() };
+        let Some(entry) = entry else {
+            return Err(Error::Custom(format!("No utxos found for {}", kaspa_addr)));
+        };
+        let utxo = (TransactionOutpoint::from(entry.outpoint), UtxoEntry::from(entry.utxo_entry));
+        Ok(Self { generator, kaspa_addr, profile, fee, utxo })
+    }
+
+    /// Builds and submits a tx carrying `msg`, logging its explorer link
+    pub async fn submit<G: Episode>(&mut self, kaspad: &KaspaRpcClient, msg: &EpisodeMessage<G>) -> Result<RpcTransactionId, Error> {
+        let tx = self.generator.build_command_transaction(self.utxo.clone(), &self.kaspa_addr, msg, self.fee);
+        info!("Submitting: {}", tx.id());
+        let tx_id = submit_transaction(kaspad, &tx).await?;
+        if let Some(url) = utils::explorer_tx_url(&self.profile, tx.id()) {
+            info!("Explorer: {}", url);
+        }
+        self.utxo = get_first_output_utxo(&tx);
+        Ok(tx_id)
+    }
+}
+
+/// Waits for Ctrl-C or, on unix, SIGTERM as sent by `kill` and container runtimes. A handler which fails to register is
+/// logged and leaves the wait to the other one
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Raises `exit_signal` on Ctrl-C or SIGTERM, stopping the listener which in turn signals the engines to exit. The
+/// returned flag tells whether the interruption happened
+pub fn exit_on_ctrl_c(exit_signal: Arc<AtomicBool>) -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    tokio::spawn({
+        let interrupted = interrupted.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Interrupted, shutting down...");
+            interrupted.store(true, Ordering::Relaxed);
+            exit_signal.store(true, Ordering::Relaxed);
+        }
+    });
+    interrupted
+}
+
 pub type EngineMap = HashMap<PrefixType, (PatternType, Sender<Msg>)>;
 
-pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signal: Arc<AtomicBool>) {
+/// Interval for notifying engines of the current virtual DAA score. This drives episode expiration
+/// even when no episode txs are being accepted
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub async fn run_listener(
+    kaspad: KaspaRpcClient,
+    network_id: NetworkId,
+    rpc_urls: Vec<String>,
+    engines: EngineMap,
+    exit_signal: Arc<AtomicBool>,
+) {
     let info = kaspad.get_block_dag_info().await.unwrap();
-    let mut sink = info.sink;
+    run_listener_from(kaspad, network_id, rpc_urls, engines, exit_signal, info.sink, |_| {}, Default::default()).await
+}
+
+/// Runs the listener starting from `start_hash`, which is expected to be a chain block within the pruning window.
+/// All episode txs accepted since this block are replayed to the engines before following the DAG tip, allowing
+/// episode state to be reconstructed from chain history after a restart.
+///
+/// When an RPC call fails, the listener reconnects through `rpc_urls` the way [`connect_client_with_failover`] does
+/// and resumes from the last chain block it forwarded. Other clients pinned to the original node are not moved.
+///
+/// `on_sync_point` is called with the last processed chain block after every batch of new chain blocks, once all engines
+/// confirmed having processed the batch, so engine state is never behind a reported sync point. The listener forwards
+/// nothing further until the callback returns, hence [`crate::engine::EngineMsg::Snapshot`] requests sent from within
+/// it capture the state at exactly that sync point. Persisting the two together allows catching up on txs accepted
+/// while the listener was down by restoring the snapshots and passing the sync point back as `start_hash` on the next run.
+///
+/// Listener activity is recorded into `metrics`, which can be sampled concurrently.
+pub async fn run_listener_from(
+    mut kaspad: KaspaRpcClient,
+    network_id: NetworkId,
+    rpc_urls: Vec<String>,
+    engines: EngineMap,
+    exit_signal: Arc<AtomicBool>,
+    start_hash: Hash,
+    mut on_sync_point: impl FnMut(Hash),
+    metrics: Arc<ListenerMetrics>,
+) {
+    let mut sink = start_hash;
     let mut now = Instant::now();
-    info!("Sink: {}", sink);
+    let mut next_tick = now;
+    info!("Listening from chain block: {}", sink);
     loop {
         if exit_signal.load(Ordering::Relaxed) {
             info!("Exiting...");
@@ -83,119 +315,302 @@ pub async fn run_listener(kaspad: KaspaRpcClient, engines: EngineMap, exit_signa
         sleep_until(now + Duration::from_secs(1)).await;
         now = Instant::now();
 
-        let vcb = kaspad.get_virtual_chain_from_block(sink, true).await.unwrap();
-
-        debug!("vspc: {}, {}", vcb.removed_chain_block_hashes.len(), vcb.accepted_transaction_ids.len());
+        if now >= next_tick {
+            // Ticks only drive expiration, so a failed query merely skips one
+            match kaspad.get_block_dag_info().await {
+                Ok(info) => {
+                    for (_, sender) in engines.values() {
+                        sender.send(Msg::Tick { daa_score: info.virtual_daa_score }).unwrap();
+                        metrics.messages_sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Err(err) => {
+                    warn!("Failed to query the virtual DAA score, skipping tick: {}", err);
+                    fail_over(&mut kaspad, network_id, &rpc_urls).await;
+                }
+            }
+            next_tick = now + TICK_INTERVAL;
+        }
 
-        if let Some(new_sink) = vcb.accepted_transaction_ids.last().map(|ncb| ncb.accepting_block_hash) {
-            sink = new_sink;
-        } else {
+        // A failure midway leaves the engines untouched, so the same update is simply fetched again
+        let update = match fetch_chain_update(&kaspad, &engines, sink, &metrics).await {
+            Ok(Some(update)) => update,
             // No new added chain blocks. This means no removed chain blocks as well so we can continue
-            continue;
-        }
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Failed to fetch the chain update from {}: {}", sink, err);
+                fail_over(&mut kaspad, network_id, &rpc_urls).await;
+                continue;
+            }
+        };
+        sink = update.sink;
+
+        metrics.chain_blocks_removed.fetch_add(update.removed.len() as u64, Ordering::Relaxed);
+        metrics.chain_blocks_added.fetch_add(update.added, Ordering::Relaxed);
+        metrics.pattern_matches.fetch_add(update.pattern_matches, Ordering::Relaxed);
 
-        for rcb in vcb.removed_chain_block_hashes {
+        for rcb in update.removed {
             for (_, sender) in engines.values() {
                 let msg = Msg::BlkReverted { accepting_hash: rcb };
                 sender.send(msg).unwrap();
+                metrics.messages_sent.fetch_add(1, Ordering::Relaxed);
             }
         }
 
-        // Iterate new chain blocks
-        for ncb in vcb.accepted_transaction_ids {
-            let accepting_hash = ncb.accepting_block_hash;
+        for AcceptedTxs { prefix, accepting_hash, accepting_daa, accepting_time, associated_txs } in update.accepted {
+            for (tx_id, _payload) in associated_txs.iter() {
+                info!("received episode tx: {}", tx_id);
+            }
+            metrics.payloads_forwarded.fetch_add(associated_txs.len() as u64, Ordering::Relaxed);
+            let msg = Msg::BlkAccepted { accepting_hash, accepting_daa, accepting_time, associated_txs };
+            engines[&prefix].1.send(msg).unwrap();
+            metrics.messages_sent.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if engines_synced(&engines, &metrics).await {
+            on_sync_point(sink);
+        }
+    }
 
-            // Required txs kept in original acceptance order. Skip the first which is always a coinbase tx
-            let required_txs: Vec<Hash> = ncb
-                .accepted_transaction_ids
-                .iter()
-                .copied()
-                .skip(1)
-                .filter(|&id| engines.values().any(|(pattern, _)| check_pattern(id, pattern)))
-                .collect();
+    for (_, sender) in engines.values() {
+        sender.send(Msg::Exit).unwrap();
+    }
+}
 
-            // Track the required payloads
-            let mut required_payloads: HashMap<Hash, Option<Vec<u8>>> = required_txs.iter().map(|&id| (id, None)).collect();
-            let mut required_num = required_payloads.len();
+/// Waits until every engine has processed all messages sent to it so far. Returns false if an engine exited meanwhile
+async fn engines_synced(engines: &EngineMap, metrics: &ListenerMetrics) -> bool {
+    let mut confirmations = Vec::with_capacity(engines.len());
+    for (_, sender) in engines.values() {
+        let (reply, confirmation) = oneshot::channel();
+        sender.send(Msg::Sync { reply }).unwrap();
+        metrics.messages_sent.fetch_add(1, Ordering::Relaxed);
+        confirmations.push(confirmation);
+    }
+    for confirmation in confirmations {
+        if confirmation.await.is_err() {
+            return false;
+        }
+    }
+    true
+}
 
-            if required_num == 0 {
-                continue;
+/// Replaces a failing client by connecting anew through `rpc_urls`. The current client is kept if no node responds,
+/// in which case the next failure tries again
+async fn fail_over(kaspad: &mut KaspaRpcClient, network_id: NetworkId, rpc_urls: &[String]) {
+    match connect_client_with_failover(network_id, rpc_urls).await {
+        Ok(client) => {
+            if let Err(err) = kaspad.disconnect().await {
+                debug!("Disconnecting the failed client: {}", err);
             }
+            info!("Listener failed over to {}", client.url().unwrap_or_default());
+            *kaspad = client;
+        }
+        Err(err) => warn!("Failing over found no responsive node: {}", err),
+    }
+}
 
-            let accepting_block = kaspad.get_block(accepting_hash, false).await.unwrap(); // no need for txs of this block itself
-            let verbose = accepting_block.verbose_data.unwrap();
-            assert_eq!(verbose.selected_parent_hash, verbose.merge_set_blues_hashes[0]);
-            debug!(
-                "accepting block: {}, selected parent: {}, mergeset len: {}",
-                accepting_hash,
-                verbose.selected_parent_hash,
-                verbose.merge_set_blues_hashes.len() + verbose.merge_set_reds_hashes.len()
-            );
-
-            // Iterate over merged blocks until finding all accepted and required txs (the mergeset is guaranteed to contain these txs)
-            'outer: for merged_hash in verbose.merge_set_blues_hashes.into_iter().chain(verbose.merge_set_reds_hashes) {
-                let merged_block = kaspad.get_block(merged_hash, true).await.unwrap();
-                for tx in merged_block.transactions.into_iter().skip(1) {
-                    if let Some(required_payload) = required_payloads.get_mut(&tx.verbose_data.unwrap().transaction_id) {
-                        if required_payload.is_none() {
-                            required_payload.replace(tx.payload);
-                            required_num -= 1;
-                            if required_num == 0 {
-                                break 'outer;
-                            }
+/// Txs accepted by a single chain block and routed to the engine of `prefix`
+struct AcceptedTxs {
+    prefix: PrefixType,
+    accepting_hash: Hash,
+    accepting_daa: u64,
+    accepting_time: u64,
+    associated_txs: AssociatedTxs,
+}
+
+/// The chain changes since a previous sink, with everything fetched that is needed to forward them to the engines
+struct ChainUpdate {
+    sink: Hash,
+    removed: Vec<Hash>,
+    added: u64,
+    pattern_matches: u64,
+    accepted: Vec<AcceptedTxs>,
+}
+
+/// Fetches the chain blocks removed and added since `sink`, along with the payloads of accepted txs matching an engine.
+/// Returns None if no chain blocks were added
+async fn fetch_chain_update(
+    kaspad: &KaspaRpcClient,
+    engines: &EngineMap,
+    sink: Hash,
+    metrics: &ListenerMetrics,
+) -> Result<Option<ChainUpdate>, Error> {
+    let vcb = kaspad.get_virtual_chain_from_block(sink, true).await?;
+
+    debug!("vspc: {}, {}", vcb.removed_chain_block_hashes.len(), vcb.accepted_transaction_ids.len());
+
+    let Some(new_sink) = vcb.accepted_transaction_ids.last().map(|ncb| ncb.accepting_block_hash) else {
+        return Ok(None);
+    };
+    let added = vcb.accepted_transaction_ids.len() as u64;
+    let mut pattern_matches = 0;
+    let mut accepted = vec![];
+
+    // Iterate new chain blocks
+    for ncb in vcb.accepted_transaction_ids {
+        let accepting_hash = ncb.accepting_block_hash;
+
+        // Required txs kept in original acceptance order. Skip the first which is always a coinbase tx
+        let required_txs: Vec<Hash> = ncb
+            .accepted_transaction_ids
+            .iter()
+            .copied()
+            .skip(1)
+            .filter(|&id| engines.values().any(|(pattern, _)| check_pattern(id, pattern)))
+            .collect();
+
+        // Track the required payloads
+        let mut required_payloads: HashMap<Hash, Option<Vec<u8>>> = required_txs.iter().map(|&id| (id, None)).collect();
+        let mut required_num = required_payloads.len();
+
+        if required_num == 0 {
+            continue;
+        }
+        pattern_matches += required_num as u64;
+
+        let accepting_block = kaspad.get_block(accepting_hash, false).await?; // no need for txs of this block itself
+        metrics.blocks_fetched.fetch_add(1, Ordering::Relaxed);
+        let verbose = accepting_block.verbose_data.unwrap();
+        assert_eq!(verbose.selected_parent_hash, verbose.merge_set_blues_hashes[0]);
+        debug!(
+            "accepting block: {}, selected parent: {}, mergeset len: {}",
+            accepting_hash,
+            verbose.selected_parent_hash,
+            verbose.merge_set_blues_hashes.len() + verbose.merge_set_reds_hashes.len()
+        );
+
+        // Iterate over merged blocks until finding all accepted and required txs (the mergeset is guaranteed to contain these txs)
+        'outer: for merged_hash in verbose.merge_set_blues_hashes.into_iter().chain(verbose.merge_set_reds_hashes) {
+            let merged_block = kaspad.get_block(merged_hash, true).await?;
+            metrics.blocks_fetched.fetch_add(1, Ordering::Relaxed);
+            for tx in merged_block.transactions.into_iter().skip(1) {
+                if let Some(required_payload) = required_payloads.get_mut(&tx.verbose_data.unwrap().transaction_id) {
+                    if required_payload.is_none() {
+                        required_payload.replace(tx.payload);
+                        required_num -= 1;
+                        if required_num == 0 {
+                            break 'outer;
                         }
                     }
                 }
             }
-            assert_eq!(0, required_num, "kaspad is misbehaving");
-            // info!("Tx payloads: {:?}", required_payloads);
-
-            let mut consumed_txs = 0;
-            // Iterate over all engines and look for id pattern + prefix
-            for (&prefix, (pattern, sender)) in engines.iter() {
-                // Collect and strip payloads in the correct order (as maintained by required_txs)
-                let associated_txs: Vec<_> = required_txs
-                    .iter()
-                    .filter_map(|&id| {
-                        // First, check the pattern
-                        if !check_pattern(id, pattern) {
-                            return None;
-                        }
-                        match required_payloads.entry(id) {
-                            Entry::Occupied(entry) => {
-                                // The prefix is unique per engine, so once we find a match we can consume the entry
-                                if Payload::check_header(entry.get().as_ref().unwrap(), prefix) {
-                                    let payload = entry.remove().unwrap();
-                                    consumed_txs += 1;
-                                    return Some((id, Payload::strip_header(payload)));
-                                }
-                            }
-                            Entry::Vacant(_) => {}
-                        }
-                        None
-                    })
-                    .collect();
-                for (tx_id, _payload) in associated_txs.iter() {
-                    info!("received episode tx: {}", tx_id);
-                }
-                if !associated_txs.is_empty() {
-                    let msg = Msg::BlkAccepted {
-                        accepting_hash,
-                        accepting_daa: accepting_block.header.daa_score,
-                        accepting_time: accepting_block.header.timestamp,
-                        associated_txs,
-                    };
-                    sender.send(msg).unwrap();
+        }
+        assert_eq!(0, required_num, "kaspad is misbehaving");
+        // info!("Tx payloads: {:?}", required_payloads);
+
+        for (prefix, associated_txs) in route_payloads(engines, &required_txs, required_payloads) {
+            accepted.push(AcceptedTxs {
+                prefix,
+                accepting_hash,
+                accepting_daa: accepting_block.header.daa_score,
+                accepting_time: accepting_block.header.timestamp,
+                associated_txs,
+            });
+        }
+    }
+
+    Ok(Some(ChainUpdate { sink: new_sink, removed: vcb.removed_chain_block_hashes, added, pattern_matches, accepted }))
+}
+
+/// Accepted txs associated with an engine, as (tx id, stripped payload) pairs
+type AssociatedTxs = Vec<(Hash, Vec<u8>)>;
+
+/// Routes the payloads of txs accepted by a single chain block to engines by id pattern and payload prefix.
+/// Payloads are stripped of their header and kept in acceptance order (as maintained by `required_txs`).
+/// Returns the associated txs of each engine with at least one match.
+fn route_payloads(
+    engines: &EngineMap,
+    required_txs: &[Hash],
+    mut required_payloads: HashMap<Hash, Option<Vec<u8>>>,
+) -> Vec<(PrefixType, AssociatedTxs)> {
+    let mut routed = vec![];
+    let mut consumed_txs = 0;
+    // Iterate over all engines and look for id pattern + prefix
+    for (&prefix, (pattern, _)) in engines.iter() {
+        // Collect and strip payloads in the correct order (as maintained by required_txs)
+        let associated_txs: Vec<_> = required_txs
+            .iter()
+            .filter_map(|&id| {
+                // First, check the pattern
+                if !check_pattern(id, pattern) {
+                    return None;
                 }
-                if consumed_txs == required_txs.len() {
-                    // No need to check additional engines
-                    break;
+                match required_payloads.entry(id) {
+                    Entry::Occupied(entry) => {
+                        // The prefix is unique per engine, so once we find a match we can consume the entry
+                        if Payload::check_header(entry.get().as_ref().unwrap(), prefix) {
+                            let payload = entry.remove().unwrap();
+                            consumed_txs += 1;
+                            return Some((id, Payload::strip_header(payload)));
+                        }
+                    }
+                    Entry::Vacant(_) => {}
                 }
-            }
+                None
+            })
+            .collect();
+        if !associated_txs.is_empty() {
+            routed.push((prefix, associated_txs));
+        }
+        if consumed_txs == required_txs.len() {
+            // No need to check additional engines
+            break;
         }
     }
+    routed
+}
 
-    for (_, sender) in engines.values() {
-        sender.send(Msg::Exit).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::pattern_from_prefix;
+    use std::sync::mpsc::channel;
+
+    /// Builds a tx id matching `pattern`, with `salt` written into a byte outside the pattern positions
+    fn matching_id(pattern: &PatternType, salt: u8) -> Hash {
+        let mut bytes = [0u8; 32];
+        for &(pos, val) in pattern.iter() {
+            bytes[pos as usize / 8] |= val << (pos % 8);
+        }
+        let free = (0..32).find(|i| pattern.iter().all(|&(pos, _)| pos as usize / 8 != *i)).unwrap();
+        bytes[free] = salt;
+        Hash::from_bytes(bytes)
+    }
+
+    #[test]
+    fn test_classify_submit_failure() {
+        let tx_id: Hash = 7u64.into();
+        let duplicate = format!("Rejected transaction {}: transaction {} is already in the mempool", tx_id, tx_id);
+        assert_eq!(SubmitFailure::classify(&duplicate), SubmitFailure::Duplicate);
+        let accepted = format!("Rejected transaction {}: transaction {} was already accepted by the consensus", tx_id, tx_id);
+        assert_eq!(SubmitFailure::classify(&accepted), SubmitFailure::Duplicate);
+        let orphan = format!("Rejected transaction {}: transaction {} is an orphan", tx_id, tx_id);
+        assert_eq!(SubmitFailure::classify(&orphan), SubmitFailure::Orphan);
+        let invalid = format!("Rejected transaction {}: transaction {} has insufficient fee", tx_id, tx_id);
+        assert_eq!(SubmitFailure::classify(&invalid), SubmitFailure::Rejected);
+        assert_eq!(SubmitFailure::classify("WebSocket disconnected"), SubmitFailure::Transport);
+    }
+
+    #[test]
+    fn test_route_payloads_by_prefix() {
+        let (p1, p2) = (1111, 2222);
+        let (pat1, pat2) = (pattern_from_prefix(p1), pattern_from_prefix(p2));
+        let engines: EngineMap = [(p1, (pat1, channel().0)), (p2, (pat2, channel().0))].into_iter().collect();
+
+        let (a, b, c, d) = (matching_id(&pat1, 1), matching_id(&pat2, 2), matching_id(&pat1, 3), matching_id(&pat1, 4));
+        let required_txs = vec![a, b, c, d];
+        let required_payloads = [
+            (a, Some(Payload::pack_header(vec![1], p1))),
+            (b, Some(Payload::pack_header(vec![2], p2))),
+            (c, Some(Payload::pack_header(vec![3], p1))),
+            // Matches the pattern of the first engine but carries an unknown prefix
+            (d, Some(Payload::pack_header(vec![4], 3333))),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut routed = route_payloads(&engines, &required_txs, required_payloads);
+        routed.sort_by_key(|(prefix, _)| *prefix);
+        assert_eq!(routed, vec![(p1, vec![(a, vec![1]), (c, vec![3])]), (p2, vec![(b, vec![2])])]);
     }
 }
@@ -0,0 +1,106 @@
+//! Helpers for presenting Kaspa data to users: address validation, KAS amount and acceptance formatting and block explorer links
+
+use crate::episode::PayloadMetadata;
+use kaspa_addresses::{Address, AddressError, Prefix};
+use kaspa_consensus_core::{constants::SOMPI_PER_KASPA, Hash};
+use thiserror::Error;
+
+pub mod network;
+
+use network::NetworkProfile;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AddressValidationError {
+    #[error("invalid address: {0}")]
+    Invalid(#[from] AddressError),
+
+    #[error("address is for network {found}, expected {expected}")]
+    WrongNetwork { expected: Prefix, found: Prefix },
+}
+
+/// Parses an address, verifying it belongs to the network of the given prefix
+pub fn parse_address(address: &str, prefix: Prefix) -> Result<Address, AddressValidationError> {
+    let address = Address::try_from(address.trim())?;
+    if address.prefix != prefix {
+        return Err(AddressValidationError::WrongNetwork { expected: prefix, found: address.prefix });
+    }
+    Ok(address)
+}
+
+/// Formats a sompi amount in KAS, dropping trailing zero decimals, e.g. `1.5 KAS`
+pub fn format_kas(sompi: u64) -> String {
+    let (whole, fraction) = (sompi / SOMPI_PER_KASPA, sompi % SOMPI_PER_KASPA);
+    if fraction == 0 {
+        return format!("{} KAS", whole);
+    }
+    let decimals = format!("{:08}", fraction);
+    format!("{}.{} KAS", whole, decimals.trim_end_matches('0'))
+}
+
+/// Describes when and where the transaction carrying a command was accepted, e.g.
+/// `confirmed at DAA score 42 (13:05:09 UTC) by block <hash>, tx <id>`
+pub fn format_acceptance(metadata: &PayloadMetadata) -> String {
+    let secs = metadata.accepting_time / 1000;
+    format!(
+        "confirmed at DAA score {} ({:02}:{:02}:{:02} UTC) by block {}, tx {}",
+        metadata.accepting_daa,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        metadata.accepting_hash,
+        metadata.tx_id
+    )
+}
+
+/// Returns a block explorer link for the given path on the network of the given profile, if it has a public explorer
+fn explorer_url(profile: &NetworkProfile, path: &str) -> Option<String> {
+    profile.explorer_url.map(|base| format!("{}/{}", base, path))
+}
+
+pub fn explorer_tx_url(profile: &NetworkProfile, tx_id: Hash) -> Option<String> {
+    explorer_url(profile, &format!("txs/{}", tx_id))
+}
+
+pub fn explorer_address_url(profile: &NetworkProfile, address: &Address) -> Option<String> {
+    explorer_url(profile, &format!("addresses/{}", address))
+}
+
+pub fn explorer_block_url(profile: &NetworkProfile, block_hash: Hash) -> Option<String> {
+    explorer_url(profile, &format!("blocks/{}", block_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kaspa_addresses::Version;
+    use kaspa_consensus_core::network::{NetworkId, NetworkType};
+
+    #[test]
+    fn test_address_and_amount_helpers() {
+        let address = Address::new(Prefix::Testnet, Version::PubKey, &[7; 32]);
+        let encoded = String::from(&address);
+        assert_eq!(parse_address(&format!(" {} ", encoded), Prefix::Testnet), Ok(address.clone()));
+        assert_eq!(
+            parse_address(&encoded, Prefix::Mainnet),
+            Err(AddressValidationError::WrongNetwork { expected: Prefix::Mainnet, found: Prefix::Testnet })
+        );
+        assert!(matches!(parse_address("kaspatest:invalid", Prefix::Testnet), Err(AddressValidationError::Invalid(_))));
+        assert_eq!(
+            explorer_address_url(&NetworkProfile::TESTNET_10, &address),
+            Some(format!("https://explorer-tn10.kaspa.org/addresses/{}", encoded))
+        );
+        assert_eq!(explorer_block_url(&NetworkProfile::DEVNET, 1u64.into()), None);
+        // Testnets share an address prefix but not an explorer
+        assert_eq!(NetworkProfile::from_network_id(NetworkId::with_suffix(NetworkType::Testnet, 11)), None);
+        assert_eq!(NetworkProfile::from_network_id(NetworkProfile::DEVNET.network_id).map(|p| p.prefix()), Some(Prefix::Devnet));
+
+        assert_eq!(format_kas(0), "0 KAS");
+        assert_eq!(format_kas(5000), "0.00005 KAS");
+        assert_eq!(format_kas(150_000_000), "1.5 KAS");
+
+        let metadata =
+            PayloadMetadata { accepting_hash: 1u64.into(), accepting_daa: 42, accepting_time: 90_309_000, tx_id: 2u64.into() };
+        let expected = format!("confirmed at DAA score 42 (01:05:09 UTC) by block {}, tx {}", Hash::from(1u64), Hash::from(2u64));
+        assert_eq!(format_acceptance(&metadata), expected);
+    }
+}
@@ -0,0 +1,45 @@
+//! Per-network settings, kept in one place so that supporting another network only means adding a profile
+
+use kaspa_addresses::Prefix;
+use kaspa_consensus_core::network::{NetworkId, NetworkType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkProfile {
+    pub network_id: NetworkId,
+    /// Fee in sompi attached to each episode transaction unless configured otherwise
+    pub default_fee: u64,
+    /// Block explorer base URL, if the network has a public one
+    pub explorer_url: Option<&'static str>,
+    pub faucet_url: Option<&'static str>,
+}
+
+impl NetworkProfile {
+    pub const MAINNET: Self = Self {
+        network_id: NetworkId::new(NetworkType::Mainnet),
+        default_fee: 5000,
+        explorer_url: Some("https://explorer.kaspa.org"),
+        faucet_url: None,
+    };
+
+    pub const TESTNET_10: Self = Self {
+        network_id: NetworkId::with_suffix(NetworkType::Testnet, 10),
+        default_fee: 5000,
+        explorer_url: Some("https://explorer-tn10.kaspa.org"),
+        faucet_url: Some("https://faucet.kaspanet.io"),
+    };
+
+    /// A local devnet, which has neither a public explorer nor a faucet. There are no public nodes either, so
+    /// a wRPC URL must be given explicitly
+    pub const DEVNET: Self =
+        Self { network_id: NetworkId::new(NetworkType::Devnet), default_fee: 5000, explorer_url: None, faucet_url: None };
+
+    pub const ALL: [Self; 3] = [Self::MAINNET, Self::TESTNET_10, Self::DEVNET];
+
+    pub fn prefix(&self) -> Prefix {
+        self.network_id.into()
+    }
+
+    pub fn from_network_id(network_id: NetworkId) -> Option<Self> {
+        Self::ALL.into_iter().find(|profile| profile.network_id == network_id)
+    }
+}